@@ -1,7 +1,8 @@
 use std::iter;
 
-use bloomy::BloomFilter;
-use criterion::Criterion;
+use bloomy::blocked::BlockLayout;
+use bloomy::{BlockedBloomFilter, BloomFilter};
+use criterion::{BenchmarkId, Criterion};
 
 fn key() -> String {
     let rng = fastrand::Rng::new();
@@ -59,5 +60,66 @@ fn bench_bloom_filter_check(c: &mut Criterion) {
     });
 }
 
-criterion::criterion_group!(benches, bench_bloom_filter_insert, bench_bloom_filter_check);
+fn bench_blocked_bloom_filter_check(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check-blocked");
+
+    for nbits in [1_000_000, 10_000_000, 100_000_000] {
+        // Around 10 bits per item, for a 1% false positive rate.
+        let n = nbits / 10;
+        let rng = fastrand::Rng::new();
+
+        let mut bf = BloomFilter::<u64>::with_size(nbits / 8);
+        for i in 0..n as u64 {
+            bf.insert(&i);
+        }
+        group.bench_with_input(BenchmarkId::new("classic", nbits), &bf, |b, bf| {
+            b.iter(|| bf.contains(&rng.u64(..)));
+        });
+
+        for (name, layout) in [
+            ("block-512", BlockLayout::Block512),
+            ("split-block-256", BlockLayout::SplitBlock256),
+        ] {
+            let mut bf = BlockedBloomFilter::<u64>::with_layout(n, 0.01, layout);
+            for i in 0..n as u64 {
+                bf.insert(&i);
+            }
+            group.bench_with_input(BenchmarkId::new(name, nbits), &bf, |b, bf| {
+                b.iter(|| bf.contains(&rng.u64(..)));
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_bloom_filter_check_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check-batch");
+    let nbits = 100_000_000;
+    let n = nbits / 10;
+    let rng = fastrand::Rng::new();
+
+    let mut bf = BloomFilter::<u64>::with_size(nbits / 8);
+    for i in 0..n as u64 {
+        bf.insert(&i);
+    }
+    let items = iter::repeat_with(|| rng.u64(..))
+        .take(1024)
+        .collect::<Vec<_>>();
+
+    group.bench_function("one-by-one", |b| {
+        b.iter(|| items.iter().filter(|i| bf.contains(i)).count());
+    });
+    group.bench_function("batch", |b| {
+        b.iter(|| bf.contains_batch(&items));
+    });
+    group.finish();
+}
+
+criterion::criterion_group!(
+    benches,
+    bench_bloom_filter_insert,
+    bench_bloom_filter_check,
+    bench_blocked_bloom_filter_check,
+    bench_bloom_filter_check_batch
+);
 criterion::criterion_main!(benches);
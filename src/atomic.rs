@@ -0,0 +1,222 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A lock-free Bloom filter, which can be shared across threads.
+//!
+//! Bits are stored in atomic words, so that items can be inserted through a shared
+//! reference. Setting a bit is an atomic `OR`, which commutes with every other insert, so
+//! relaxed ordering is enough for bits to never be lost: once an insert returns, the item
+//! is seen by all later lookups on any thread that synchronized with it, and an item is
+//! never seen as absent once all of its bits are set. Concurrent lookups of an item that
+//! is being inserted may see it as present or absent.
+//!
+//! The bit layout is the same as that of [`BloomFilter`], so that filters can be converted
+//! both ways.
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, BloomFilter, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// A Bloom filter that keeps track of items of type `K`, and supports concurrent inserts.
+#[derive(Debug)]
+pub struct AtomicBloomFilter<K> {
+    words: Vec<AtomicU64>,
+    nbits: usize,
+    nhashes: usize,
+    hashers: [SipHasher13; 2],
+    /// The filter doesn't own items, so it is `Send` and `Sync` whatever the item type.
+    key: PhantomData<fn(&K)>,
+}
+
+impl<K: Hash> AtomicBloomFilter<K> {
+    /// Return a new atomic Bloom filter with a given approximate item capacity.
+    /// The default false positive probability is used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new atomic Bloom filter with a given approximate item capacity
+    /// and a desired false positive rate.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        BloomFilter::with_rate(capacity, fp_rate).into()
+    }
+
+    /// Add an item to the filter.
+    pub fn insert(&self, item: &K) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        for i in 0..self.nhashes {
+            let index = bloom::bloom_hash(h1, h2, i as u64, self.nbits as u64) as usize;
+            self.words[index / 64].fetch_or(1 << (index % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        (0..self.nhashes).all(|i| {
+            let index = bloom::bloom_hash(h1, h2, i as u64, self.nbits as u64) as usize;
+            self.words[index / 64].load(Ordering::Relaxed) & (1 << (index % 64)) != 0
+        })
+    }
+}
+
+impl<K> AtomicBloomFilter<K> {
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.nbits
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Set all bits to zero. Concurrent inserts may or may not be kept.
+    pub fn clear(&self) {
+        for word in &self.words {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Return a plain Bloom filter with a snapshot of the bits. Concurrent inserts may or
+    /// may not be included.
+    pub fn to_bloom_filter(&self) -> BloomFilter<K> {
+        let bytes = self
+            .words
+            .iter()
+            .flat_map(|w| w.load(Ordering::Relaxed).to_le_bytes())
+            .take(self.nbits.div_ceil(8))
+            .collect();
+        let bits = BitVec::from_bytes(bytes, self.nbits).unwrap();
+
+        BloomFilter::from_parts(bits, self.nhashes)
+    }
+}
+
+impl<K> From<BloomFilter<K>> for AtomicBloomFilter<K> {
+    fn from(other: BloomFilter<K>) -> Self {
+        let words = other
+            .as_bytes()
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+
+                AtomicU64::new(u64::from_le_bytes(word))
+            })
+            .collect();
+
+        Self {
+            words,
+            nbits: other.bits(),
+            nhashes: other.hashes(),
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K> From<AtomicBloomFilter<K>> for BloomFilter<K> {
+    fn from(other: AtomicBloomFilter<K>) -> Self {
+        other.to_bloom_filter()
+    }
+}
+
+impl<K> Clone for AtomicBloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            words: self
+                .words
+                .iter()
+                .map(|w| AtomicU64::new(w.load(Ordering::Relaxed)))
+                .collect(),
+            nbits: self.nbits,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<AtomicBloomFilter<u32>>();
+        assert_send_sync::<AtomicBloomFilter<Rc<u32>>>();
+    }
+
+    #[test]
+    fn test_conversion() {
+        // A size that isn't a multiple of the word size.
+        let mut bf = BloomFilter::<u32>::with_rate(1000, 0.01);
+        assert_ne!(bf.bits() % 64, 0);
+
+        for i in 0..500 {
+            bf.insert(&i);
+        }
+        let abf = AtomicBloomFilter::from(bf.clone());
+
+        for i in 500..1000 {
+            bf.insert(&i);
+            abf.insert(&i);
+        }
+        assert_eq!(abf.bits(), bf.bits());
+        assert_eq!(BloomFilter::from(abf.clone()), bf);
+
+        abf.clear();
+        assert_eq!(abf.to_bloom_filter().count(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let threads = 8;
+        let items = 20_000;
+        let abf = AtomicBloomFilter::<u32>::new(threads * items);
+
+        thread::scope(|s| {
+            for t in 0..threads as u32 {
+                let abf = &abf;
+
+                s.spawn(move || {
+                    for i in 0..items as u32 {
+                        let item = t * items as u32 + i;
+
+                        abf.insert(&item);
+                        assert!(abf.contains(&item), "item {} was lost", item);
+
+                        // Check an earlier item of this thread.
+                        let earlier = t * items as u32 + i / 2;
+                        assert!(abf.contains(&earlier), "item {} was lost", earlier);
+                    }
+                });
+            }
+        });
+
+        let mut bf = BloomFilter::<u32>::new(threads * items);
+
+        for item in 0..(threads * items) as u32 {
+            assert!(
+                abf.contains(&item),
+                "item {} resulted in a false negative",
+                item
+            );
+            bf.insert(&item);
+        }
+        // Inserts on any thread, in any order, result in the same bits.
+        assert_eq!(abf.to_bloom_filter(), bf);
+    }
+}
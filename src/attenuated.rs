@@ -0,0 +1,219 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! An attenuated Bloom filter, for routing queries to the peers that can likely reach an
+//! item.
+//!
+//! As described by Rhea and Kubiatowicz in *Probabilistic Location and Routing*, an
+//! attenuated Bloom filter of depth `d` is an array of `d` Bloom filters, where level `i`
+//! summarizes the items that are `i` hops away: level `0` holds the local items, level `1`
+//! the items of direct neighbors, and so on. A node keeps one such filter per neighbor, and
+//! forwards a query to the neighbor whose filter finds the item at the nearest level.
+//!
+//! Filters are propagated by merging a neighbor's filter one level deeper, so that the
+//! neighbor's level `i` is unioned into level `i + 1`, and its deepest level is dropped.
+//! Aging pushes all remote levels one level deeper, so that routes which are not refreshed
+//! by merges look farther away, and eventually expire.
+use std::hash::Hash;
+
+use crate::bloom::{BloomFilter, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// A stack of Bloom filters of items of type `K`, where level `i` holds the items `i` hops
+/// away.
+#[derive(Debug)]
+pub struct AttenuatedBloomFilter<K> {
+    levels: Vec<BloomFilter<K>>,
+}
+
+impl<K: Hash> AttenuatedBloomFilter<K> {
+    /// Return a new attenuated Bloom filter of the given depth, where each level has a
+    /// given approximate item capacity. The default false positive probability is used.
+    pub fn new(capacity: usize, depth: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE, depth)
+    }
+
+    /// Return a new attenuated Bloom filter of the given depth, where each level has a
+    /// given approximate item capacity and a desired false positive rate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the depth is zero.
+    pub fn with_rate(capacity: usize, fp_rate: f64, depth: usize) -> Self {
+        assert!(depth > 0, "depth must be greater than zero");
+
+        Self {
+            levels: vec![BloomFilter::with_rate(capacity, fp_rate); depth],
+        }
+    }
+
+    /// Add a local item to the filter, ie. at level zero.
+    pub fn insert(&mut self, item: &K) {
+        self.levels[0].insert(item);
+    }
+
+    /// Return whether or not a given item is likely reachable, at any level.
+    pub fn contains(&self, item: &K) -> bool {
+        self.nearest_level(item).is_some()
+    }
+
+    /// Return the first level that likely contains the given item, ie. its likely
+    /// distance in hops, or `None` if it isn't reachable within the depth of the filter.
+    pub fn nearest_level(&self, item: &K) -> Option<usize> {
+        self.levels.iter().position(|level| level.contains(item))
+    }
+
+    /// Merge the filter advertised by a neighbor, one hop away. Level `i` of the neighbor
+    /// is unioned into level `i + 1`, and the deepest level of the neighbor is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the filters are not comparable.
+    pub fn merge(&mut self, neighbor: &Self) {
+        assert!(
+            self.is_comparable(neighbor),
+            "unable to merge filters with different configurations"
+        );
+        for i in (1..self.levels.len()).rev() {
+            self.levels[i] = self.levels[i].union(&neighbor.levels[i - 1]);
+        }
+    }
+
+    /// Age the remote levels of the filter: every level but the first is moved one level
+    /// deeper, the deepest level is dropped, and level one is cleared. Local items are
+    /// kept. Routes that are not refreshed by [`AttenuatedBloomFilter::merge`] expire after
+    /// `depth - 1` steps.
+    pub fn age(&mut self) {
+        if self.levels.len() < 2 {
+            return;
+        }
+        let mut deepest = self.levels.pop().unwrap();
+
+        deepest.clear();
+        self.levels.insert(1, deepest);
+    }
+}
+
+impl<K> AttenuatedBloomFilter<K> {
+    /// Return a filter made of the given levels, from the nearest to the farthest.
+    /// Returns `None` if there are no levels.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the levels are not all comparable.
+    pub fn from_levels(levels: Vec<BloomFilter<K>>) -> Option<Self> {
+        let first = levels.first()?;
+
+        assert!(
+            levels.iter().all(|level| first.is_comparable(level)),
+            "unable to stack filters with different configurations"
+        );
+        Some(Self { levels })
+    }
+
+    /// Return the number of levels.
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Return the filter at the given level, if any.
+    pub fn level(&self, level: usize) -> Option<&BloomFilter<K>> {
+        self.levels.get(level)
+    }
+
+    /// Return all levels, from the nearest to the farthest.
+    pub fn levels(&self) -> &[BloomFilter<K>] {
+        &self.levels
+    }
+
+    /// Check whether two filters have the same depth, and comparable levels.
+    pub fn is_comparable(&self, other: &Self) -> bool {
+        self.levels.len() == other.levels.len() && self.levels[0].is_comparable(&other.levels[0])
+    }
+
+    /// Remove all items, at all levels.
+    pub fn clear(&mut self) {
+        self.levels.iter_mut().for_each(|level| level.clear());
+    }
+}
+
+impl<K> Clone for AttenuatedBloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            levels: self.levels.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        // A line of peers: a - b - c - d.
+        let mut peers = ["a", "b", "c", "d"].map(|name| {
+            let mut abf = AttenuatedBloomFilter::<&str>::new(100, 3);
+            abf.insert(&name);
+            abf
+        });
+        // Propagate towards `a`, twice so that information travels two hops.
+        for _ in 0..2 {
+            for i in 0..3 {
+                let neighbor = peers[i + 1].clone();
+                peers[i].merge(&neighbor);
+            }
+        }
+        let a = &peers[0];
+
+        assert_eq!(a.nearest_level(&"a"), Some(0));
+        assert_eq!(a.nearest_level(&"b"), Some(1));
+        assert_eq!(a.nearest_level(&"c"), Some(2));
+        // Beyond the depth of the filter.
+        assert_eq!(a.nearest_level(&"d"), None);
+        assert!(a.contains(&"c"));
+        assert!(!a.contains(&"e"));
+    }
+
+    #[test]
+    fn test_age() {
+        let mut a = AttenuatedBloomFilter::<u32>::new(100, 3);
+        let mut b = AttenuatedBloomFilter::<u32>::new(100, 3);
+
+        a.insert(&1);
+        b.insert(&2);
+        a.merge(&b);
+        assert_eq!(a.nearest_level(&2), Some(1));
+
+        a.age();
+        assert_eq!(a.nearest_level(&1), Some(0));
+        assert_eq!(a.nearest_level(&2), Some(2));
+        assert_eq!(a.level(1).unwrap().count(), 0);
+
+        a.age();
+        assert_eq!(a.nearest_level(&1), Some(0));
+        assert_eq!(a.nearest_level(&2), None);
+        assert_eq!(a.depth(), 3);
+    }
+
+    #[test]
+    fn test_from_levels() {
+        let mut levels = vec![BloomFilter::<u32>::new(100); 4];
+        levels[2].insert(&7);
+
+        let abf = AttenuatedBloomFilter::from_levels(levels.clone()).unwrap();
+
+        assert_eq!(abf.levels(), levels.as_slice());
+        assert_eq!(abf.nearest_level(&7), Some(2));
+        assert!(AttenuatedBloomFilter::<u32>::from_levels(Vec::new()).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_merge_incomparable() {
+        let mut a = AttenuatedBloomFilter::<u32>::new(100, 3);
+        let b = AttenuatedBloomFilter::<u32>::new(100, 4);
+
+        a.merge(&b);
+    }
+}
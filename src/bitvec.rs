@@ -4,28 +4,59 @@
 // Licensed under the MIT license.
 
 //! Bit vector functionality.
+//!
+//! Bits are stored in `u64` words, so that bulk operations such as unions, intersections
+//! and population counts process 64 bits at a time, in loops that the compiler can
+//! vectorize. The bytes of the words in memory are in the same order as the serialized
+//! bytes of the vector, ie. bit `i` is bit `i % 8` of byte `i / 8`, on all platforms: on
+//! big-endian platforms, words are stored byte-swapped. Bits past the end of the vector
+//! are always zero.
 use std::fmt::Debug;
+use std::ops::Range;
+
+/// Number of words processed together by bulk operations.
+const LANES: usize = 8;
 
 /// A packed bit vector.
 #[derive(Clone, PartialEq, Eq)]
 pub struct BitVec {
-    bytes: Vec<u8>,
+    words: Vec<u64>,
     nbits: usize,
 }
 
 impl BitVec {
     /// Create a new bit vector of the given capacity, in bits.
     pub fn new(capacity: usize) -> Self {
-        let byte_length = if capacity % 8 == 0 {
-            capacity / 8
-        } else {
-            1 + capacity / 8
-        };
-
         Self {
             nbits: capacity,
-            bytes: vec![0; byte_length],
+            words: vec![0; capacity.div_ceil(64)],
+        }
+    }
+
+    /// Create a bit vector of the given length, in bits, from its underlying bytes.
+    /// Returns `None` if the number of bytes doesn't match the length.
+    pub fn from_bytes(bytes: Vec<u8>, nbits: usize) -> Option<Self> {
+        if bytes.len() != nbits.div_ceil(8) {
+            return None;
+        }
+        let words = bytes
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+
+                u64::from_ne_bytes(word)
+            })
+            .collect();
+        let mut bits = Self { words, nbits };
+
+        // Clear the padding bits of the last word.
+        if let Some(last) = bits.words.last_mut() {
+            if nbits % 64 != 0 {
+                *last &= ((1u64 << (nbits % 64)) - 1).to_le();
+            }
         }
+        Some(bits)
     }
 
     /// Get the length in bits of the vector.
@@ -40,7 +71,7 @@ impl BitVec {
 
     /// Set all bits to zero.
     pub fn clear(&mut self) {
-        self.bytes.iter_mut().for_each(|b| *b = 0);
+        self.words.iter_mut().for_each(|w| *w = 0);
     }
 
     /// Set a single bit to `1`.
@@ -52,10 +83,19 @@ impl BitVec {
                 index,
             )
         }
-        let byte_index = index / 8;
-        let mask = 0x01 << (index % 8);
+        self.words[index / 64] |= mask(index);
+    }
 
-        self.bytes[byte_index] |= mask;
+    /// Set a single bit to `0`.
+    pub fn unset(&mut self, index: usize) {
+        if index >= self.len() {
+            panic!(
+                "index out of bounds: the len is {} but the index is {}",
+                self.len(),
+                index,
+            )
+        }
+        self.words[index / 64] &= !mask(index);
     }
 
     /// Check whether a bit is set.
@@ -67,15 +107,36 @@ impl BitVec {
                 index,
             )
         }
-        let byte_index = index / 8;
-        let mask = 0x01 << (index % 8);
-
-        self.bytes[byte_index] & mask == mask
+        self.words[index / 64] & mask(index) != 0
     }
 
     /// Count the number of `1` bits.
     pub fn count_ones(&self) -> usize {
-        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+        popcount(&self.words, &self.words, |a, _| a)
+    }
+
+    /// Count the number of `1` bits within a range of indices.
+    pub(crate) fn count_ones_in(&self, range: Range<usize>) -> usize {
+        assert!(
+            range.start <= range.end && range.end <= self.nbits,
+            "range out of bounds: the len is {} but the range is {:?}",
+            self.nbits,
+            range
+        );
+        if range.is_empty() {
+            return 0;
+        }
+        let (first, last) = (range.start / 64, (range.end - 1) / 64);
+        let head = u64::from_le(self.words[first]) & (u64::MAX << (range.start % 64));
+
+        if first == last {
+            let tail = u64::MAX >> (63 - (range.end - 1) % 64);
+            return (head & tail).count_ones() as usize;
+        }
+        let tail = u64::from_le(self.words[last]) & (u64::MAX >> (63 - (range.end - 1) % 64));
+        let middle = &self.words[first + 1..last];
+
+        (head.count_ones() + tail.count_ones()) as usize + popcount(middle, middle, |a, _| a)
     }
 
     /// Count the number of `0` bits.
@@ -86,60 +147,167 @@ impl BitVec {
     /// Return the union of two bit vectors.
     /// This is a bitwise `OR` of two vectors.
     pub fn union(&self, other: &Self) -> Self {
-        if self.nbits != other.nbits {
-            panic!(
-                "unable to union bitvecs with different lengths: {} and {}",
-                self.nbits, other.nbits
-            );
-        }
-        Self {
-            bytes: self
-                .bytes
-                .iter()
-                .zip(other.bytes.iter())
-                .map(|(a, b)| a | b)
-                .collect(),
-            nbits: self.nbits,
-        }
+        let mut union = self.clone();
+        union.union_with(other);
+        union
     }
 
     /// Return the intersection of two bit vectors.
     /// This is a bitwise `AND` of two vectors.
     pub fn intersection(&self, other: &Self) -> Self {
+        let mut intersection = self.clone();
+        intersection.intersect_with(other);
+        intersection
+    }
+
+    /// Set this vector to its union with another vector, in place.
+    pub fn union_with(&mut self, other: &Self) {
+        self.assert_same_length(other, "union");
+        apply(&mut self.words, &other.words, |a, b| a | b);
+    }
+
+    /// Set this vector to its intersection with another vector, in place.
+    pub fn intersect_with(&mut self, other: &Self) {
+        self.assert_same_length(other, "intersect");
+        apply(&mut self.words, &other.words, |a, b| a & b);
+    }
+
+    /// Unset the bits of this vector that are set in another vector, in place.
+    /// This is a bitwise `AND NOT` of two vectors.
+    pub fn difference_with(&mut self, other: &Self) {
+        self.assert_same_length(other, "subtract");
+        apply(&mut self.words, &other.words, |a, b| a & !b);
+    }
+
+    /// Count the number of `1` bits in the union of two vectors, without allocating it.
+    pub fn union_count(&self, other: &Self) -> usize {
+        self.assert_same_length(other, "union");
+        popcount(&self.words, &other.words, |a, b| a | b)
+    }
+
+    /// Count the number of `1` bits in the intersection of two vectors, without
+    /// allocating it.
+    pub fn intersection_count(&self, other: &Self) -> usize {
+        self.assert_same_length(other, "intersect");
+        popcount(&self.words, &other.words, |a, b| a & b)
+    }
+
+    /// Fold the vector into its first `nbits` bits: bit `i` of the result is the `OR` of the
+    /// bits at offset `i` of each slice of `nbits` bits.
+    pub(crate) fn fold(&self, nbits: usize) -> Self {
+        assert!(
+            nbits > 0 && self.nbits % nbits == 0,
+            "unable to fold {} bits into {} bits",
+            self.nbits,
+            nbits
+        );
+        let mut folded = Self::new(nbits);
+
+        for start in (0..self.nbits).step_by(nbits) {
+            for (i, word) in folded.words.iter_mut().enumerate() {
+                *word |= self.word_at(start + i * 64).to_le();
+            }
+        }
+        // The last word of each slice reads into the next slice.
+        if let Some(last) = folded.words.last_mut() {
+            if nbits % 64 != 0 {
+                *last &= ((1u64 << (nbits % 64)) - 1).to_le();
+            }
+        }
+        folded
+    }
+
+    /// Return the underlying bytes storage.
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.nbits.div_ceil(8);
+
+        assert!(len <= self.words.len() * 8);
+        // SAFETY: the words are initialized and live as long as `self`, any byte is a
+        // valid `u8`, `u8` has no alignment requirement, and `len` doesn't exceed the
+        // size of the words, in bytes.
+        unsafe { std::slice::from_raw_parts(self.words.as_ptr() as *const u8, len) }
+    }
+
+    /// Return the 64 bits starting at the given index, bit `i` of the result being bit
+    /// `index + i` of the vector. Bits past the end of the vector are zero.
+    fn word_at(&self, index: usize) -> u64 {
+        let (word, offset) = (index / 64, index % 64);
+        let lo = self.words.get(word).map_or(0, |w| u64::from_le(*w));
+
+        if offset == 0 {
+            return lo;
+        }
+        let hi = self.words.get(word + 1).map_or(0, |w| u64::from_le(*w));
+
+        (lo >> offset) | (hi << (64 - offset))
+    }
+
+    fn assert_same_length(&self, other: &Self, operation: &str) {
         if self.nbits != other.nbits {
             panic!(
-                "unable to intersect bitvecs with different lengths: {} and {}",
-                self.nbits, other.nbits
+                "unable to {} bitvecs with different lengths: {} and {}",
+                operation, self.nbits, other.nbits
             );
         }
-        Self {
-            bytes: self
-                .bytes
-                .iter()
-                .zip(other.bytes.iter())
-                .map(|(a, b)| a & b)
-                .collect(),
-            nbits: self.nbits,
+    }
+}
+
+/// Return the mask of a bit within its word. Words hold bytes in serialization order, so
+/// the mask is byte-swapped on big-endian platforms.
+#[inline]
+fn mask(index: usize) -> u64 {
+    (1u64 << (index % 64)).to_le()
+}
+
+/// Apply a bitwise operation to each pair of words, in place.
+#[inline]
+fn apply(words: &mut [u64], other: &[u64], op: impl Fn(u64, u64) -> u64) {
+    let mut chunks = words.chunks_exact_mut(LANES);
+    let mut others = other.chunks_exact(LANES);
+
+    for (chunk, other) in (&mut chunks).zip(&mut others) {
+        for (a, b) in chunk.iter_mut().zip(other) {
+            *a = op(*a, *b);
         }
     }
+    for (a, b) in chunks.into_remainder().iter_mut().zip(others.remainder()) {
+        *a = op(*a, *b);
+    }
+}
 
-    /// Return the underlying bytes storage.
-    pub fn as_bytes(&self) -> &[u8] {
-        &self.bytes
+/// Count the `1` bits of a bitwise operation on each pair of words. Counts are kept in
+/// one accumulator per lane, so that words of a chunk are counted independently.
+#[inline]
+fn popcount(words: &[u64], other: &[u64], op: impl Fn(u64, u64) -> u64) -> usize {
+    let mut counts = [0u64; LANES];
+    let mut chunks = words.chunks_exact(LANES);
+    let mut others = other.chunks_exact(LANES);
+
+    for (chunk, other) in (&mut chunks).zip(&mut others) {
+        for ((count, a), b) in counts.iter_mut().zip(chunk).zip(other) {
+            *count += op(*a, *b).count_ones() as u64;
+        }
     }
+    let remainder = chunks
+        .remainder()
+        .iter()
+        .zip(others.remainder())
+        .map(|(a, b)| op(*a, *b).count_ones() as u64);
+
+    (counts.iter().copied().sum::<u64>() + remainder.sum::<u64>()) as usize
 }
 
 impl From<Vec<u8>> for BitVec {
     fn from(bytes: Vec<u8>) -> Self {
         let nbits = bytes.len() * 8;
 
-        Self { bytes, nbits }
+        Self::from_bytes(bytes, nbits).unwrap()
     }
 }
 
 impl From<BitVec> for Vec<u8> {
     fn from(other: BitVec) -> Vec<u8> {
-        other.bytes
+        other.as_bytes().to_vec()
     }
 }
 
@@ -161,17 +329,20 @@ mod tests {
         let bitvec = BitVec::new(1);
         assert_eq!(1, bitvec.nbits);
         assert_eq!(1, bitvec.len());
-        assert_eq!(1, bitvec.bytes.len());
+        assert_eq!(1, bitvec.as_bytes().len());
+        assert_eq!(1, bitvec.words.len());
 
         let bitvec = BitVec::new(8);
         assert_eq!(8, bitvec.nbits);
         assert_eq!(8, bitvec.len());
-        assert_eq!(1, bitvec.bytes.len());
+        assert_eq!(1, bitvec.as_bytes().len());
+        assert_eq!(1, bitvec.words.len());
 
         let bitvec = BitVec::new(9);
         assert_eq!(9, bitvec.nbits);
         assert_eq!(9, bitvec.len());
-        assert_eq!(2, bitvec.bytes.len());
+        assert_eq!(2, bitvec.as_bytes().len());
+        assert_eq!(1, bitvec.words.len());
     }
 
     #[test]
@@ -362,4 +533,130 @@ mod tests {
         assert_eq!(true, bitvec.is_set(3));
         assert_eq!(false, bitvec.is_set(5));
     }
+
+    #[test]
+    fn bytes_round_trip() {
+        let bytes = vec![
+            0b1010_0001,
+            0xff,
+            0x00,
+            0x80,
+            0x01,
+            0x02,
+            0x03,
+            0x04,
+            0x05,
+            0b0000_0111,
+        ];
+        let bitvec = BitVec::from_bytes(bytes.clone(), 80).unwrap();
+
+        assert_eq!(bitvec.as_bytes(), bytes.as_slice());
+        assert_eq!(true, bitvec.is_set(0));
+        assert_eq!(false, bitvec.is_set(1));
+        assert_eq!(true, bitvec.is_set(5));
+        assert_eq!(true, bitvec.is_set(31));
+        assert_eq!(true, bitvec.is_set(74));
+        assert_eq!(Vec::from(bitvec.clone()), bytes);
+
+        let mut bitvec = BitVec::new(80);
+        for i in (0..80).filter(|i| bytes[i / 8] & (1 << (i % 8)) != 0) {
+            bitvec.set(i);
+        }
+        assert_eq!(bitvec.as_bytes(), bytes.as_slice());
+
+        // Padding bits are cleared.
+        let bitvec = BitVec::from_bytes(vec![0xff, 0xff], 10).unwrap();
+        assert_eq!(bitvec.count_ones(), 10);
+        assert_eq!(bitvec.as_bytes(), &[0xff, 0x03]);
+    }
+
+    #[test]
+    fn bitvec_in_place_ops() {
+        let mut rng = crate::rng::Rng::new(1);
+
+        for nbits in [0, 1, 63, 64, 65, 511, 512, 513, 10_000] {
+            let mut a = BitVec::new(nbits);
+            let mut b = BitVec::new(nbits);
+
+            for _ in 0..nbits / 3 {
+                a.set(rng.below(nbits as u64) as usize);
+                b.set(rng.below(nbits as u64) as usize);
+            }
+            let expected = |f: fn(bool, bool) -> bool| {
+                (0..nbits)
+                    .filter(|i| f(a.is_set(*i), b.is_set(*i)))
+                    .collect::<Vec<_>>()
+            };
+            let ones = |v: &BitVec| (0..nbits).filter(|i| v.is_set(*i)).collect::<Vec<_>>();
+
+            let mut union = a.clone();
+            union.union_with(&b);
+            assert_eq!(ones(&union), expected(|x, y| x | y));
+            assert_eq!(union, a.union(&b));
+            assert_eq!(a.union_count(&b), union.count_ones());
+
+            let mut intersection = a.clone();
+            intersection.intersect_with(&b);
+            assert_eq!(ones(&intersection), expected(|x, y| x & y));
+            assert_eq!(intersection, a.intersection(&b));
+            assert_eq!(a.intersection_count(&b), intersection.count_ones());
+
+            let mut difference = a.clone();
+            difference.difference_with(&b);
+            assert_eq!(ones(&difference), expected(|x, y| x & !y));
+
+            assert_eq!(a.count_ones(), ones(&a).len());
+        }
+    }
+
+    #[test]
+    fn count_ones_in_range() {
+        let mut rng = crate::rng::Rng::new(2);
+        let mut bitvec = BitVec::new(1000);
+
+        for _ in 0..400 {
+            bitvec.set(rng.below(1000) as usize);
+        }
+        for (start, end) in [(0, 0), (0, 1000), (3, 9), (60, 70), (64, 128), (5, 999)] {
+            assert_eq!(
+                bitvec.count_ones_in(start..end),
+                (start..end).filter(|i| bitvec.is_set(*i)).count(),
+                "range {}..{}",
+                start,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn fold() {
+        let mut rng = crate::rng::Rng::new(3);
+
+        for (len, nbits) in [
+            (64, 64),
+            (128, 64),
+            (1024, 128),
+            (120, 30),
+            (1000, 250),
+            (4, 1),
+        ] {
+            let mut bitvec = BitVec::new(len);
+
+            for _ in 0..len / 8 {
+                bitvec.set(rng.below(len as u64) as usize);
+            }
+            let mut expected = BitVec::new(nbits);
+
+            for i in (0..len).filter(|i| bitvec.is_set(*i)) {
+                expected.set(i % nbits);
+            }
+            assert_eq!(bitvec.fold(nbits), expected, "{} bits into {}", len, nbits);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "different lengths")]
+    fn must_union_with_same_length() {
+        BitVec::new(5).union_with(&BitVec::new(6));
+    }
 }
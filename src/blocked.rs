@@ -0,0 +1,385 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A cache-line blocked Bloom filter.
+//!
+//! Instead of spreading the `k` bits of an item across the whole bit vector, a blocked
+//! filter uses the first hash to pick a single block, and sets all `k` bits inside it.
+//! A lookup therefore touches a single cache line, regardless of `k`. The masks for a block
+//! are computed up-front and applied word by word, which compilers can vectorize.
+//!
+//! Two layouts are supported:
+//!
+//! * [`BlockLayout::Block512`]: 512-bit blocks, the size of a cache line, with `k` bits set
+//!   anywhere in the block using enhanced double hashing.
+//! * [`BlockLayout::SplitBlock256`]: 256-bit blocks split into eight 32-bit lanes, with
+//!   exactly one bit set per lane, as used by Apache Parquet and Impala.
+//!
+//! Since items aren't spread evenly across blocks, a blocked filter needs slightly more
+//! bits than a classic one for the same false positive rate. Use [`optimal_bits`] to size
+//! a filter accounting for this.
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom::{self, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// Salts used to derive the lane bits of a split block, one per 32-bit lane.
+const SPLIT_BLOCK_SALTS: [u32; 8] = [
+    0x47b6137b, 0x44974d91, 0x8824ad5b, 0xa2b7289d, 0x705495c7, 0x2df1424b, 0x9efc4947, 0x5c6bfb31,
+];
+
+/// Maximum number of hashes considered when sizing a 512-bit block filter.
+const MAX_HASHES: usize = 24;
+
+/// The layout of a block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockLayout {
+    /// 512-bit blocks, with `k` bits set anywhere in the block.
+    Block512,
+    /// 256-bit blocks made of eight 32-bit lanes, with one bit set per lane.
+    SplitBlock256,
+}
+
+impl BlockLayout {
+    /// Return the size of a block, in bits.
+    pub fn block_bits(&self) -> usize {
+        match self {
+            Self::Block512 => 512,
+            Self::SplitBlock256 => 256,
+        }
+    }
+}
+
+/// A cache line worth of bits. The alignment guarantees a block never straddles two
+/// cache lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C, align(64))]
+struct Block([u64; 8]);
+
+/// A blocked Bloom filter that keeps track of items of type `K`.
+#[derive(Debug)]
+pub struct BlockedBloomFilter<K> {
+    blocks: Vec<Block>,
+    /// Number of logical blocks. With split blocks, there are two per cache line.
+    nblocks: usize,
+    nhashes: usize,
+    layout: BlockLayout,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> BlockedBloomFilter<K> {
+    /// Return a new blocked Bloom filter with a given approximate item capacity.
+    /// The default false positive probability and 512-bit blocks are used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new blocked Bloom filter with 512-bit blocks, a given approximate item
+    /// capacity and a desired false positive rate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the false positive rate is not between 0 and 1.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        Self::with_layout(capacity, fp_rate, BlockLayout::Block512)
+    }
+
+    /// Return a new blocked Bloom filter with a given approximate item capacity, desired
+    /// false positive rate and block layout.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the false positive rate is not between 0 and 1.
+    pub fn with_layout(capacity: usize, fp_rate: f64, layout: BlockLayout) -> Self {
+        let nbits = optimal_bits(capacity, fp_rate, layout);
+        let nhashes = optimal_hashes(nbits, capacity, layout);
+        let nblocks = nbits / layout.block_bits();
+        let ncachelines = match layout {
+            BlockLayout::Block512 => nblocks,
+            BlockLayout::SplitBlock256 => nblocks.div_ceil(2),
+        };
+
+        Self {
+            blocks: vec![Block::default(); ncachelines],
+            nblocks,
+            nhashes,
+            layout,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Set an item in the filter. This operation is idempotent with regards
+    /// to each unique item.
+    pub fn insert(&mut self, item: &K) {
+        let (index, mask) = self.mask(item);
+        let block = &mut self.blocks[index];
+
+        for (word, m) in block.0.iter_mut().zip(mask.iter()) {
+            *word |= m;
+        }
+    }
+
+    /// Return whether or not a given item is likely in the filter or not.
+    pub fn contains(&self, item: &K) -> bool {
+        let (index, mask) = self.mask(item);
+        let block = &self.blocks[index];
+
+        block
+            .0
+            .iter()
+            .zip(mask.iter())
+            .fold(true, |acc, (word, m)| acc & (word & m == *m))
+    }
+
+    /// Return the cache line an item maps to, and the mask of bits to set within it.
+    fn mask(&self, item: &K) -> (usize, [u64; 8]) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        let block = (h1 % self.nblocks as u64) as usize;
+        let mut mask = [0u64; 8];
+
+        match self.layout {
+            BlockLayout::Block512 => {
+                for i in 0..self.nhashes {
+                    let bit = bloom::bloom_hash(h2, h1.rotate_left(32), i as u64, 512) as usize;
+                    mask[bit / 64] |= 1 << (bit % 64);
+                }
+                (block, mask)
+            }
+            BlockLayout::SplitBlock256 => {
+                let h = (h2 >> 32) as u32;
+                let half = (block % 2) * 4;
+
+                for (lane, salt) in SPLIT_BLOCK_SALTS.iter().enumerate() {
+                    let bit = h.wrapping_mul(*salt) >> 27;
+                    mask[half + lane / 2] |= 1 << ((lane % 2) * 32 + bit as usize);
+                }
+                (block / 2, mask)
+            }
+        }
+    }
+}
+
+impl<K> BlockedBloomFilter<K> {
+    /// Set all bits to zero.
+    pub fn clear(&mut self) {
+        self.blocks.iter_mut().for_each(|b| *b = Block::default());
+    }
+
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.nblocks * self.layout.block_bits()
+    }
+
+    /// Number of bits set per item (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Return the number of blocks in this filter.
+    pub fn blocks(&self) -> usize {
+        self.nblocks
+    }
+
+    /// Return the block layout of this filter.
+    pub fn layout(&self) -> BlockLayout {
+        self.layout
+    }
+
+    /// Return the expected false positive rate of this filter once it holds the given
+    /// number of items.
+    pub fn fp_rate(&self, items: usize) -> f64 {
+        false_positive_rate(self.bits(), items, self.nhashes, self.layout)
+    }
+}
+
+impl<K> Clone for BlockedBloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            blocks: self.blocks.clone(),
+            nblocks: self.nblocks,
+            nhashes: self.nhashes,
+            layout: self.layout,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+/// Return the expected false positive rate of a blocked filter with the given number of
+/// bits, items and hashes.
+///
+/// The number of items per block follows a Poisson distribution, and each block behaves
+/// like a small classic filter, so the rate is the average of the per-block rates,
+/// weighted by the probability of each block load.
+pub fn false_positive_rate(
+    nbits: usize,
+    capacity: usize,
+    nhashes: usize,
+    layout: BlockLayout,
+) -> f64 {
+    let block_bits = layout.block_bits() as f64;
+    let lambda = capacity as f64 * block_bits / nbits as f64;
+    let max = (lambda + 12. * lambda.sqrt() + 20.).ceil() as usize;
+    let mut ln_factorial = 0.;
+    let mut rate = 0.;
+
+    for i in 0..=max {
+        if i > 0 {
+            ln_factorial += (i as f64).ln();
+        }
+        let ln_p = -lambda + i as f64 * lambda.ln() - ln_factorial;
+        let p = if lambda > 0. {
+            ln_p.exp()
+        } else if i == 0 {
+            1.
+        } else {
+            0.
+        };
+        let block_rate = match layout {
+            BlockLayout::Block512 => {
+                let zero = (1. - 1. / block_bits).powf((i * nhashes) as f64);
+                (1. - zero).powi(nhashes as i32)
+            }
+            BlockLayout::SplitBlock256 => {
+                let zero = (1. - 1. / 32_f64).powi(i as i32);
+                (1. - zero).powi(8)
+            }
+        };
+        rate += p * block_rate;
+    }
+    rate
+}
+
+/// Return the number of bits a blocked filter needs to hold the given number of items with
+/// the desired false positive rate. This is rounded up to a whole number of blocks, and is
+/// larger than [`bloom::optimal_bits`] to make up for uneven block loads.
+///
+/// # Panics
+///
+/// Panics if the false positive rate is not between 0 and 1.
+pub fn optimal_bits(capacity: usize, fp_rate: f64, layout: BlockLayout) -> usize {
+    assert!(
+        fp_rate > 0. && fp_rate < 1.,
+        "false positive rate must be between 0 and 1, got {}",
+        fp_rate
+    );
+    let block_bits = layout.block_bits();
+    let capacity = capacity.max(1);
+    let mut nbits = bloom::optimal_bits(capacity, fp_rate).max(block_bits);
+
+    loop {
+        let nblocks = nbits.div_ceil(block_bits);
+        let rounded = nblocks * block_bits;
+        let nhashes = optimal_hashes(rounded, capacity, layout);
+
+        if false_positive_rate(rounded, capacity, nhashes, layout) <= fp_rate {
+            return rounded;
+        }
+        nbits = rounded + usize::max(block_bits, rounded / 100);
+    }
+}
+
+/// Return the number of bits to set per item for a blocked filter with the given number
+/// of bits and items. Split blocks always set one bit in each of their eight lanes.
+pub fn optimal_hashes(nbits: usize, capacity: usize, layout: BlockLayout) -> usize {
+    match layout {
+        BlockLayout::Block512 => (1..=MAX_HASHES)
+            .min_by(|a, b| {
+                let fa = false_positive_rate(nbits, capacity, *a, layout);
+                let fb = false_positive_rate(nbits, capacity, *b, layout);
+                fa.total_cmp(&fb)
+            })
+            .unwrap(),
+        BlockLayout::SplitBlock256 => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alignment() {
+        let bf = BlockedBloomFilter::<u64>::new(1000);
+
+        assert_eq!(std::mem::align_of::<Block>(), 64);
+        assert_eq!(bf.blocks.as_ptr() as usize % 64, 0);
+    }
+
+    #[test]
+    fn test_blocked_filter() {
+        for layout in [BlockLayout::Block512, BlockLayout::SplitBlock256] {
+            let n = 10_000;
+            let mut bf = BlockedBloomFilter::<u64>::with_layout(n, 0.01, layout);
+
+            assert_eq!(bf.bits() % layout.block_bits(), 0);
+
+            for i in 0..n as u64 {
+                bf.insert(&i);
+            }
+            for i in 0..n as u64 {
+                assert!(bf.contains(&i), "item {} resulted in a false negative", i);
+            }
+            let trials = 100_000;
+            let false_positives = (n as u64..(n + trials) as u64)
+                .filter(|i| bf.contains(i))
+                .count();
+            let rate = false_positives as f64 / trials as f64;
+
+            assert!(rate < 0.015, "{:?}: false positive rate {}", layout, rate);
+
+            bf.clear();
+            assert!(!bf.contains(&0));
+        }
+    }
+
+    #[test]
+    fn test_mask_bits() {
+        let bf = BlockedBloomFilter::<u64>::with_layout(1000, 0.01, BlockLayout::SplitBlock256);
+
+        for i in 0..100u64 {
+            let (_, mask) = bf.mask(&i);
+            let ones: u32 = mask.iter().map(|m| m.count_ones()).sum();
+
+            assert_eq!(ones, 8);
+            // Only one half of the cache line is used.
+            assert!(mask[..4] == [0; 4] || mask[4..] == [0; 4]);
+        }
+    }
+
+    #[test]
+    fn test_optimal_bits() {
+        for fp_rate in [0.1, 0.01, 0.001] {
+            let classic = bloom::optimal_bits(100_000, fp_rate);
+
+            for layout in [BlockLayout::Block512, BlockLayout::SplitBlock256] {
+                let nbits = optimal_bits(100_000, fp_rate, layout);
+                let nhashes = optimal_hashes(nbits, 100_000, layout);
+
+                assert!(nbits >= classic);
+                assert!(false_positive_rate(nbits, 100_000, nhashes, layout) <= fp_rate);
+            }
+        }
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        // A blocked filter is always worse than a classic filter of the same size.
+        let classic = (1. - (-7. * 10_000. / 95_851_f64).exp()).powi(7);
+        let blocked = false_positive_rate(95_851, 10_000, 7, BlockLayout::Block512);
+
+        assert!(blocked > classic);
+        assert!(blocked < classic * 1.5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_rate() {
+        BlockedBloomFilter::<u32>::with_rate(1000, 0.);
+    }
+}
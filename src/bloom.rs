@@ -8,6 +8,7 @@
 use std::f64;
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::thread;
 
 use siphasher::sip::SipHasher13;
 
@@ -19,6 +20,12 @@ pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
 /// `ln` squared.
 const LN_SQR: f64 = f64::consts::LN_2 * f64::consts::LN_2;
 
+/// Minimum number of items hashed by each thread when inserting in parallel.
+const MIN_ITEMS_PER_THREAD: usize = 1 << 14;
+
+/// Number of items whose bits are prefetched together in batch lookups.
+const PREFETCH_BATCH: usize = 32;
+
 /// Seeds used for SipHash.
 const HASHER_SEEDS: [[u8; 16]; 2] = [
     [
@@ -30,7 +37,7 @@ const HASHER_SEEDS: [[u8; 16]; 2] = [
 ];
 
 /// A Bloom filter that keeps track of items of type `K`.
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct BloomFilter<K> {
     bits: BitVec,
     nhashes: usize,
@@ -50,17 +57,8 @@ impl<K: Hash> BloomFilter<K> {
         let nbits = nbytes * 8;
         let capacity = optimal_capacity(nbits, DEFAULT_FALSE_POSITIVE_RATE);
         let nhashes = optimal_hashes(nbits, capacity);
-        let hashers = [
-            SipHasher13::new_with_key(&HASHER_SEEDS[0]),
-            SipHasher13::new_with_key(&HASHER_SEEDS[1]),
-        ];
 
-        BloomFilter {
-            bits: BitVec::new(nbits),
-            nhashes,
-            hashers,
-            key: PhantomData,
-        }
+        BloomFilter::from_parts(BitVec::new(nbits), nhashes)
     }
 
     /// Return a new Bloom filter with a given approximate item capacity
@@ -68,17 +66,29 @@ impl<K: Hash> BloomFilter<K> {
     pub fn with_rate(capacity: usize, fp_rate: f64) -> BloomFilter<K> {
         let nbits = optimal_bits(capacity, fp_rate);
         let nhashes = optimal_hashes(nbits, capacity);
-        let hashers = [
-            SipHasher13::new_with_key(&HASHER_SEEDS[0]),
-            SipHasher13::new_with_key(&HASHER_SEEDS[1]),
-        ];
 
-        BloomFilter {
-            bits: BitVec::new(nbits),
-            nhashes,
-            hashers,
-            key: PhantomData,
-        }
+        BloomFilter::from_parts(BitVec::new(nbits), nhashes)
+    }
+
+    /// Return a new Bloom filter with a given approximate item capacity and a desired
+    /// false positive rate, whose size is a multiple of the given power of two, so that it
+    /// can be folded by that factor, or any smaller power of two.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the factor is not a power of two.
+    pub fn with_fold_factor(capacity: usize, fp_rate: f64, factor: usize) -> BloomFilter<K> {
+        assert!(
+            factor.is_power_of_two(),
+            "fold factor must be a power of two, got {}",
+            factor
+        );
+        let nbits = optimal_bits(capacity, fp_rate)
+            .max(1)
+            .next_multiple_of(factor);
+        let nhashes = optimal_hashes(nbits, capacity);
+
+        BloomFilter::from_parts(BitVec::new(nbits), nhashes)
     }
 
     /// Set an item in the Bloom filter. This operation is idempotent with regards
@@ -107,29 +117,114 @@ impl<K: Hash> BloomFilter<K> {
         true
     }
 
-    /// Set all bits to zero.
-    pub fn clear(&mut self) {
-        self.bits.clear();
-    }
+    /// Return a new Bloom filter with a given approximate item capacity and a desired false
+    /// positive rate, holding the given items, which are hashed on multiple threads. See
+    /// [`BloomFilter::extend_parallel`].
+    pub fn from_par_iter<I>(capacity: usize, fp_rate: f64, items: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Sync,
+    {
+        let items = items.into_iter().collect::<Vec<_>>();
+        let mut filter = Self::with_rate(capacity, fp_rate);
+
+        filter.extend_parallel(&items);
+        filter
+    }
+
+    /// Add the given items to the filter, hashing them on multiple threads. Each thread
+    /// builds a partial filter from a chunk of the items, and the partial filters are
+    /// unioned into this one. The number of threads is the available parallelism, and is
+    /// reduced for small inputs, which are inserted on the current thread.
+    pub fn extend_parallel(&mut self, items: &[K])
+    where
+        K: Sync,
+    {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(items.len() / MIN_ITEMS_PER_THREAD);
+
+        self.extend_threads(items, threads);
+    }
+
+    /// Add the given items to the filter, hashing them on the given number of threads.
+    fn extend_threads(&mut self, items: &[K], threads: usize)
+    where
+        K: Sync,
+    {
+        if threads <= 1 {
+            items.iter().for_each(|item| self.insert(item));
+            return;
+        }
+        let (hashers, nhashes, nbits) = (self.hashers, self.nhashes, self.bits());
+
+        thread::scope(|s| {
+            let partials = items
+                .chunks(items.len().div_ceil(threads))
+                .map(|chunk| {
+                    s.spawn(move || {
+                        let mut bits = BitVec::new(nbits);
+
+                        for item in chunk {
+                            let (h1, h2) = sip_hashes(&hashers, item);
+
+                            for i in 0..nhashes {
+                                bits.set(bloom_hash(h1, h2, i as u64, nbits as u64) as usize);
+                            }
+                        }
+                        bits
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for partial in partials {
+                self.bits.union_with(&partial.join().unwrap());
+            }
+        });
+    }
+
+    /// Return whether or not each of the given items is likely in the filter, as a bit
+    /// vector where bit `i` is set if item `i` is likely in the filter.
+    ///
+    /// Items are looked up in batches: the bit indices of all items of a batch are
+    /// computed and their memory is prefetched, before any bit is tested, so that the
+    /// memory accesses of a batch overlap.
+    pub fn contains_batch(&self, items: &[K]) -> BitVec {
+        let mut result = BitVec::new(items.len());
+
+        // Without hashes, every item is in the filter, as with `contains`.
+        if self.nhashes == 0 {
+            (0..items.len()).for_each(|i| result.set(i));
+            return result;
+        }
+        let mut indices = Vec::with_capacity(PREFETCH_BATCH * self.nhashes);
 
-    /// Return the number of bits in this filter.
-    pub fn bits(&self) -> usize {
-        self.bits.len()
-    }
+        for (batch, chunk) in items.chunks(PREFETCH_BATCH).enumerate() {
+            indices.clear();
 
-    /// Number of hashes used (`k` parameter).
-    pub fn hashes(&self) -> usize {
-        self.nhashes
+            for item in chunk {
+                let (h1, h2) = self.sip_hashes(item);
+
+                for i in 0..self.nhashes {
+                    let index = self.bloom_hash(h1, h2, i as u64) as usize;
+
+                    prefetch(&self.bits.as_bytes()[index / 8]);
+                    indices.push(index);
+                }
+            }
+            for (i, indices) in indices.chunks(self.nhashes).enumerate() {
+                if indices.iter().all(|index| self.bits.is_set(*index)) {
+                    result.set(batch * PREFETCH_BATCH + i);
+                }
+            }
+        }
+        result
     }
 
     /// Count the approximate number of items in the filter.
     pub fn count(&self) -> usize {
-        let nbits = self.bits.len() as f64;
-        let nbits_set = self.bits.count_ones() as f64;
-        let nhashes = self.nhashes as f64;
-        let count = -(nbits / nhashes) * (1. - (nbits_set / nbits)).ln();
-
-        count.round() as usize
+        self.estimate_count(self.bits.count_ones())
     }
 
     /// Compute the approximate similarity between two filters using the Jaccard Index.
@@ -138,8 +233,8 @@ impl<K: Hash> BloomFilter<K> {
             self.is_comparable(other),
             "unable to compare filters with different configurations"
         );
-        let intersection = self.intersection(other).count() as f64;
-        let union = self.union(other).count() as f64;
+        let intersection = self.estimate_count(self.bits.intersection_count(&other.bits)) as f64;
+        let union = self.estimate_count(self.bits.union_count(&other.bits)) as f64;
 
         intersection / union
     }
@@ -150,7 +245,7 @@ impl<K: Hash> BloomFilter<K> {
             self.is_comparable(other),
             "unable to compare filters with different configurations"
         );
-        let intersection = self.intersection(other).count() as f64;
+        let intersection = self.estimate_count(self.bits.intersection_count(&other.bits)) as f64;
         let smallest = usize::min(self.count(), other.count()) as f64;
 
         intersection / smallest
@@ -188,6 +283,42 @@ impl<K: Hash> BloomFilter<K> {
         }
     }
 
+    /// Estimate the number of items of a filter of this size with the given number of bits
+    /// set.
+    fn estimate_count(&self, nbits_set: usize) -> usize {
+        let nbits = self.bits.len() as f64;
+        let nbits_set = nbits_set as f64;
+        let nhashes = self.nhashes as f64;
+        let count = -(nbits / nhashes) * (1. - (nbits_set / nbits)).ln();
+
+        count.round() as usize
+    }
+
+    fn sip_hashes(&self, item: &K) -> (u64, u64) {
+        sip_hashes(&self.hashers, item)
+    }
+
+    fn bloom_hash(&self, h1: u64, h2: u64, i: u64) -> u64 {
+        bloom_hash(h1, h2, i, self.bits() as u64)
+    }
+}
+
+impl<K> BloomFilter<K> {
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Set all bits to zero.
+    pub fn clear(&mut self) {
+        self.bits.clear();
+    }
+
     /// Check whether two filters can be compared, intersected and unioned.
     pub fn is_comparable(&self, other: &Self) -> bool {
         self.nhashes == other.nhashes
@@ -196,34 +327,177 @@ impl<K: Hash> BloomFilter<K> {
             && self.hashers[1].keys() == other.hashers[1].keys()
     }
 
+    /// Return the hashers used to derive bit indices.
+    pub(crate) fn hashers(&self) -> &[SipHasher13; 2] {
+        &self.hashers
+    }
+
+    /// Fold the filter by a power of two, dividing its size by the factor. Each bit of
+    /// the folded filter is the `OR` of the bits at the same offset in each of the `factor`
+    /// slices of the filter.
+    ///
+    /// Since bit indices are derived by reducing a hash modulo the size of the filter, and
+    /// `(h mod m) mod (m / f) = h mod (m / f)` when `f` divides `m`, the folded filter
+    /// contains all the items of the original filter, with a higher false positive rate.
+    /// Filters created with [`BloomFilter::with_fold_factor`] can be folded by that factor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the factor is not a power of two, or doesn't divide the size of the filter.
+    pub fn fold(&self, factor: usize) -> Self {
+        assert!(
+            factor.is_power_of_two(),
+            "fold factor must be a power of two, got {}",
+            factor
+        );
+        assert!(
+            self.bits() % factor == 0,
+            "unable to fold a filter of {} bits by a factor of {}",
+            self.bits(),
+            factor
+        );
+        Self {
+            bits: self.bits.fold(self.bits() / factor),
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+
+    /// Check whether two filters can be unioned with [`BloomFilter::union_folding`], ie.
+    /// they are comparable once the larger one is folded to the size of the smaller one.
+    pub fn is_foldable(&self, other: &Self) -> bool {
+        let (small, large) = if self.bits() <= other.bits() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        small.bits() > 0
+            && large.bits() % small.bits() == 0
+            && (large.bits() / small.bits()).is_power_of_two()
+            && self.nhashes == other.nhashes
+            && self.hashers[0].keys() == other.hashers[0].keys()
+            && self.hashers[1].keys() == other.hashers[1].keys()
+    }
+
+    /// Compute the union of two filters of possibly different sizes, by folding the larger
+    /// filter to the size of the smaller one first. The result has the size of the smaller
+    /// filter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the filters are not foldable to the same size, see
+    /// [`BloomFilter::is_foldable`].
+    pub fn union_folding(&self, other: &Self) -> Self {
+        assert!(
+            self.is_foldable(other),
+            "unable to union filters with different configurations"
+        );
+        let (small, large) = if self.bits() <= other.bits() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let mut bits = large.fold(large.bits() / small.bits()).bits;
+        bits.union_with(&small.bits);
+
+        Self {
+            bits,
+            nhashes: small.nhashes,
+            hashers: small.hashers,
+            key: small.key,
+        }
+    }
+
     /// Return the underlying bytes storage.
     pub fn as_bytes(&self) -> &[u8] {
         self.bits.as_bytes()
     }
 
-    fn sip_hashes(&self, item: &K) -> (u64, u64) {
-        let mut sip1 = self.hashers[0];
-        let mut sip2 = self.hashers[1];
+    /// Return the underlying bit vector.
+    pub(crate) fn bit_vec(&self) -> &BitVec {
+        &self.bits
+    }
+
+    /// Build a filter from an existing bit vector and number of hashes, using the default
+    /// hashers.
+    pub(crate) fn from_parts(bits: BitVec, nhashes: usize) -> Self {
+        Self {
+            bits,
+            nhashes,
+            hashers: hashers(),
+            key: PhantomData,
+        }
+    }
+}
 
-        item.hash(&mut sip1);
-        item.hash(&mut sip2);
+/// Return the pair of SipHash instances used to derive item hashes.
+pub(crate) fn hashers() -> [SipHasher13; 2] {
+    [
+        SipHasher13::new_with_key(&HASHER_SEEDS[0]),
+        SipHasher13::new_with_key(&HASHER_SEEDS[1]),
+    ]
+}
 
-        let h1 = sip1.finish();
-        let h2 = sip2.finish();
+/// Hash an item with both hashers, returning `H1(x)` and `H2(x)`.
+pub(crate) fn sip_hashes<K: Hash + ?Sized>(hashers: &[SipHasher13; 2], item: &K) -> (u64, u64) {
+    let mut sip1 = hashers[0];
+    let mut sip2 = hashers[1];
 
-        (h1, h2)
-    }
+    item.hash(&mut sip1);
+    item.hash(&mut sip2);
 
-    fn bloom_hash(&self, h1: u64, h2: u64, i: u64) -> u64 {
-        let r = h1.wrapping_add(i.wrapping_mul(h2)).wrapping_add(i.pow(3));
-        r % self.bits() as u64
+    let h1 = sip1.finish();
+    let h2 = sip2.finish();
+
+    (h1, h2)
+}
+
+/// Hash an item with a single hasher.
+pub(crate) fn sip_hash<K: Hash + ?Sized>(hasher: &SipHasher13, item: &K) -> u64 {
+    let mut hasher = *hasher;
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Scramble the bits of a hash, with the MurmurHash3 finalizer.
+pub(crate) fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+/// Compute the `i`-th enhanced double hash of an item, modulo `m`.
+pub(crate) fn bloom_hash(h1: u64, h2: u64, i: u64, m: u64) -> u64 {
+    let r = h1.wrapping_add(i.wrapping_mul(h2)).wrapping_add(i.pow(3));
+    r % m
+}
+
+/// Hint the processor to load the given byte into its cache.
+#[inline(always)]
+fn prefetch(byte: &u8) {
+    #[cfg(target_arch = "x86_64")]
+    // SAFETY: prefetching has no effect on program state, and the pointer is valid.
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+        _mm_prefetch::<_MM_HINT_T0>(byte as *const u8 as *const i8);
     }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = byte;
 }
 
 /// Return the optimal bit vector size for a Bloom filter given an approximate
 /// size and a desired false positive rate.
 pub fn optimal_bits(capacity: usize, fp_rate: f64) -> usize {
-    (-((fp_rate.ln() * (capacity as f64)) / LN_SQR)).ceil() as usize
+    usize::try_from(optimal_bits_u64(capacity as u64, fp_rate)).unwrap_or(usize::MAX)
+}
+
+/// Return the optimal bit vector size as a `u64`, for filters that may not fit in a `usize`.
+pub(crate) fn optimal_bits_u64(capacity: u64, fp_rate: f64) -> u64 {
+    (-((fp_rate.ln() * (capacity as f64)) / LN_SQR)).ceil() as u64
 }
 
 /// Return the optimal item capacity of a filter given a bit vector size and false positive rate.
@@ -236,7 +510,12 @@ pub fn optimal_capacity(nbits: usize, fp_rate: f64) -> usize {
 ///
 /// Also called `k`.
 pub fn optimal_hashes(nbits: usize, capacity: usize) -> usize {
-    (((nbits / capacity) as f64) * f64::consts::LN_2).ceil() as usize
+    optimal_hashes_u64(nbits as u64, capacity as u64)
+}
+
+/// Return the optimal number of hash functions given a `u64` bit vector size.
+pub(crate) fn optimal_hashes_u64(nbits: u64, capacity: u64) -> usize {
+    (((nbits / capacity.max(1)) as f64) * f64::consts::LN_2).ceil() as usize
 }
 
 impl<K> AsRef<[u8]> for BloomFilter<K> {
@@ -245,6 +524,17 @@ impl<K> AsRef<[u8]> for BloomFilter<K> {
     }
 }
 
+impl<K> Clone for BloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            bits: self.bits.clone(),
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
 impl<K> PartialEq for BloomFilter<K> {
     fn eq(&self, other: &Self) -> bool {
         self.bits == other.bits && self.nhashes == other.nhashes
@@ -258,17 +548,8 @@ impl<K> From<Vec<u8>> for BloomFilter<K> {
         let bits = BitVec::from(other);
         let capacity = optimal_capacity(bits.len(), DEFAULT_FALSE_POSITIVE_RATE);
         let nhashes = optimal_hashes(bits.len(), capacity);
-        let hashers = [
-            SipHasher13::new_with_key(&HASHER_SEEDS[0]),
-            SipHasher13::new_with_key(&HASHER_SEEDS[1]),
-        ];
 
-        Self {
-            bits,
-            nhashes,
-            hashers,
-            key: PhantomData,
-        }
+        Self::from_parts(bits, nhashes)
     }
 }
 
@@ -353,6 +634,124 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fold() {
+        let mut bf = BloomFilter::<u32>::with_fold_factor(1000, 0.001, 4);
+
+        assert_eq!(bf.bits() % 4, 0);
+
+        for i in 0..1000 {
+            bf.insert(&i);
+        }
+        let half = bf.fold(2);
+        let quarter = bf.fold(4);
+
+        assert_eq!(half.bits(), bf.bits() / 2);
+        assert_eq!(quarter, half.fold(2));
+
+        for i in 0..1000 {
+            assert!(half.contains(&i), "item {} resulted in a false negative", i);
+            assert!(
+                quarter.contains(&i),
+                "item {} resulted in a false negative",
+                i
+            );
+        }
+        let false_positives =
+            |f: &BloomFilter<u32>| (1000..11_000).filter(|i| f.contains(i)).count();
+
+        assert!(false_positives(&bf) <= false_positives(&half));
+        assert!(false_positives(&half) <= false_positives(&quarter));
+    }
+
+    #[test]
+    #[should_panic(expected = "unable to fold")]
+    fn test_fold_indivisible() {
+        BloomFilter::<u32>::from_parts(BitVec::new(10), 3).fold(4);
+    }
+
+    #[test]
+    fn test_union_folding() {
+        let mut small = BloomFilter::<u32>::with_fold_factor(100, 0.01, 1);
+        let mut large =
+            BloomFilter::<u32>::from_parts(BitVec::new(small.bits() * 8), small.hashes());
+        let other = BloomFilter::<u32>::from_parts(BitVec::new(small.bits() * 3), small.hashes());
+
+        for i in 0..50 {
+            small.insert(&i);
+        }
+        for i in 50..100 {
+            large.insert(&i);
+        }
+        assert!(small.is_foldable(&large));
+        assert!(!small.is_foldable(&other));
+
+        let union = large.union_folding(&small);
+
+        assert_eq!(union.bits(), small.bits());
+        assert_eq!(union, small.union_folding(&large));
+
+        for i in 0..100 {
+            assert!(
+                union.contains(&i),
+                "item {} resulted in a false negative",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_extend_parallel() {
+        let n = 200_000;
+        let bf = BloomFilter::<u64>::from_par_iter(n, 0.01, 0..n as u64);
+        let mut expected = BloomFilter::<u64>::with_rate(n, 0.01);
+
+        for i in 0..n as u64 {
+            expected.insert(&i);
+        }
+        assert_eq!(bf, expected);
+
+        // Whatever the number of threads, the bits are the same.
+        for threads in [2, 3, 8] {
+            let mut bf = BloomFilter::<u64>::with_rate(n, 0.01);
+            bf.extend_threads(&(0..n as u64).collect::<Vec<_>>(), threads);
+
+            assert_eq!(bf, expected);
+        }
+
+        // Small inputs are inserted on the current thread.
+        let mut small = BloomFilter::<u64>::new(100);
+        small.extend_parallel(&[1, 2, 3]);
+
+        assert!(small.contains(&1) && small.contains(&2) && small.contains(&3));
+    }
+
+    #[test]
+    fn test_contains_batch() {
+        let mut bf = BloomFilter::<u32>::new(1000);
+
+        for i in (0..1000).step_by(2) {
+            bf.insert(&i);
+        }
+        let items = (0..1000).collect::<Vec<u32>>();
+        let result = bf.contains_batch(&items);
+
+        assert_eq!(result.len(), items.len());
+        for (i, item) in items.iter().enumerate() {
+            assert_eq!(result.is_set(i), bf.contains(item));
+        }
+        assert_eq!(bf.contains_batch(&[]).len(), 0);
+
+        // Fewer bits than items, for which the optimal number of hashes is zero.
+        let bf = BloomFilter::<u32>::with_rate(100, 0.9);
+        let result = bf.contains_batch(&items[..100]);
+
+        assert_eq!(bf.hashes(), 0);
+        for (i, item) in items[..100].iter().enumerate() {
+            assert_eq!(result.is_set(i), bf.contains(item));
+        }
+    }
+
     #[test]
     fn test_intersection() {
         let mut a = BloomFilter::<u8>::new(3);
@@ -449,6 +848,7 @@ mod tests {
         assert_eq!(optimal_hashes(67, 10), 5);
         assert_eq!(optimal_hashes(47926, 5000), 7);
         assert_eq!(optimal_hashes(958506, 100000), 7);
+        assert_eq!(optimal_hashes(0, 0), 0);
     }
 
     #[test]
@@ -0,0 +1,323 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A filter cascade, for representing a set exactly against a known universe.
+//!
+//! As in CRLite, described by Larisch et al. in *CRLite: A Scalable System for Pushing All
+//! TLS Revocations to All Browsers*, the universe is split into an *include* set and an
+//! *exclude* set. The first level is a Bloom filter of the include set; the second level
+//! is a Bloom filter of the items of the exclude set that are false positives of the first
+//! level; the third level holds the items of the include set that are false positives of
+//! the second level, and so on, until a level has no false positives. Each level hashes
+//! items with its own salt, so that false positives of one level are independent of the
+//! others.
+//!
+//! An item of the universe is in the include set if the first level that doesn't contain
+//! it is odd, or if all levels contain it and there is an odd number of levels. Items
+//! outside of the universe get an answer, but it may be wrong.
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, DEFAULT_FALSE_POSITIVE_RATE};
+use crate::encoding::{DecodeError, Reader};
+
+/// The false positive rate of levels after the first, as recommended by CRLite.
+pub const LEVEL_FALSE_POSITIVE_RATE: f64 = 0.5;
+
+/// Maximum number of levels of a cascade.
+const MAX_LEVELS: usize = 128;
+
+/// An error building a filter cascade.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The cascade didn't converge, because some item is in both sets.
+    Overlap,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overlap => write!(
+                f,
+                "unable to build cascade in {} levels, the sets likely overlap",
+                MAX_LEVELS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A single level of the cascade.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Level {
+    bits: BitVec,
+    nhashes: usize,
+}
+
+/// A cascade of Bloom filters, representing a set of items of type `K` exactly within a
+/// known universe.
+#[derive(Debug)]
+pub struct FilterCascade<K> {
+    levels: Vec<Level>,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> FilterCascade<K> {
+    /// Build a cascade of the include set, with no false positives against the exclude
+    /// set. The first level uses the default false positive rate.
+    pub fn build<I, E>(include: I, exclude: E) -> Result<Self, BuildError>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<K>,
+        E: IntoIterator,
+        E::Item: Borrow<K>,
+    {
+        Self::with_rate(include, exclude, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Build a cascade of the include set, with no false positives against the exclude
+    /// set, and the given false positive rate for the first level. Further levels use
+    /// [`LEVEL_FALSE_POSITIVE_RATE`].
+    pub fn with_rate<I, E>(include: I, exclude: E, fp_rate: f64) -> Result<Self, BuildError>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<K>,
+        E: IntoIterator,
+        E::Item: Borrow<K>,
+    {
+        let mut cascade = Self {
+            levels: Vec::new(),
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        };
+        let include = include.into_iter().collect::<Vec<_>>();
+        let exclude = exclude.into_iter().collect::<Vec<_>>();
+        let mut positives = include.iter().map(|i| i.borrow()).collect::<Vec<&K>>();
+        let mut negatives = exclude.iter().map(|e| e.borrow()).collect::<Vec<&K>>();
+        let mut rate = fp_rate;
+
+        while !positives.is_empty() {
+            if cascade.levels.len() == MAX_LEVELS {
+                return Err(BuildError::Overlap);
+            }
+            let salt = cascade.levels.len();
+            let nbits = bloom::optimal_bits(positives.len(), rate).max(1);
+            let mut level = Level {
+                bits: BitVec::new(nbits),
+                nhashes: bloom::optimal_hashes(nbits, positives.len()).max(1),
+            };
+            for item in &positives {
+                for index in cascade.indices(&level, salt, item) {
+                    level.bits.set(index);
+                }
+            }
+            let false_positives = negatives
+                .into_iter()
+                .filter(|item| {
+                    cascade
+                        .indices(&level, salt, item)
+                        .all(|index| level.bits.is_set(index))
+                })
+                .collect();
+
+            cascade.levels.push(level);
+            negatives = positives;
+            positives = false_positives;
+            rate = LEVEL_FALSE_POSITIVE_RATE;
+        }
+        Ok(cascade)
+    }
+
+    /// Return whether or not a given item is in the include set. The answer is exact for
+    /// items of the include and exclude sets the cascade was built from.
+    pub fn contains(&self, item: &K) -> bool {
+        for (salt, level) in self.levels.iter().enumerate() {
+            if !self
+                .indices(level, salt, item)
+                .all(|index| level.bits.is_set(index))
+            {
+                return salt % 2 == 1;
+            }
+        }
+        self.levels.len() % 2 == 1
+    }
+
+    /// Return the bit indices of an item at a level.
+    fn indices(&self, level: &Level, salt: usize, item: &K) -> impl Iterator<Item = usize> {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, &(salt as u32, item));
+        let nbits = level.bits.len() as u64;
+
+        (0..level.nhashes).map(move |i| bloom::bloom_hash(h1, h2, i as u64, nbits) as usize)
+    }
+}
+
+impl<K> FilterCascade<K> {
+    /// Return the number of levels.
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Return the total number of bits of all levels.
+    pub fn bits(&self) -> usize {
+        self.levels.iter().map(|l| l.bits.len()).sum()
+    }
+}
+
+impl<K> Clone for FilterCascade<K> {
+    fn clone(&self) -> Self {
+        Self {
+            levels: self.levels.clone(),
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+impl<K> PartialEq for FilterCascade<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.levels == other.levels
+    }
+}
+
+impl<K> Eq for FilterCascade<K> {}
+
+impl<K> From<FilterCascade<K>> for Vec<u8> {
+    /// Encode the cascade as its number of levels, followed by the number of hashes, the
+    /// number of bits and the bits of each level.
+    fn from(other: FilterCascade<K>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&(other.levels.len() as u32).to_le_bytes());
+
+        for level in other.levels {
+            bytes.extend_from_slice(&(level.nhashes as u32).to_le_bytes());
+            bytes.extend_from_slice(&(level.bits.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(level.bits.as_bytes());
+        }
+        bytes
+    }
+}
+
+impl<K> TryFrom<&[u8]> for FilterCascade<K> {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let nlevels = reader.u32()? as usize;
+
+        if nlevels > MAX_LEVELS {
+            return Err(DecodeError::InvalidField("levels"));
+        }
+        let mut levels = Vec::with_capacity(nlevels);
+
+        for _ in 0..nlevels {
+            let nhashes = reader.u32()? as usize;
+            let nbits = reader.usize("nbits")?;
+
+            if nhashes == 0 {
+                return Err(DecodeError::InvalidField("nhashes"));
+            }
+            if nbits == 0 {
+                return Err(DecodeError::InvalidField("nbits"));
+            }
+            let raw = reader.bytes(nbits.div_ceil(8))?;
+            let bits =
+                BitVec::from_bytes(raw.to_vec(), nbits).ok_or(DecodeError::InvalidField("bits"))?;
+
+            levels.push(Level { bits, nhashes });
+        }
+        reader.finish()?;
+
+        Ok(Self {
+            levels,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact() {
+        let revoked = (0..1000u32).map(|i| i * 7).collect::<Vec<_>>();
+        let valid = (0..100_000u32).filter(|i| i % 7 != 0).collect::<Vec<_>>();
+        let cascade = FilterCascade::<u32>::build(&revoked, &valid).unwrap();
+
+        assert!(cascade.levels() > 1);
+
+        for i in &revoked {
+            assert!(
+                cascade.contains(i),
+                "item {} resulted in a false negative",
+                i
+            );
+        }
+        for i in &valid {
+            assert!(
+                !cascade.contains(i),
+                "item {} resulted in a false positive",
+                i
+            );
+        }
+        // Much smaller than a plain filter with no false positives over the universe.
+        assert!(
+            cascade.bits() < bloom::optimal_bits(revoked.len(), 1e-5),
+            "{} bits",
+            cascade.bits()
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        let cascade = FilterCascade::<u32>::build(Vec::<u32>::new(), [1, 2, 3]).unwrap();
+
+        assert_eq!(cascade.levels(), 0);
+        assert!(!cascade.contains(&1));
+
+        let cascade = FilterCascade::<u32>::build([1, 2, 3], Vec::<u32>::new()).unwrap();
+
+        assert_eq!(cascade.levels(), 1);
+        assert!(cascade.contains(&1));
+    }
+
+    #[test]
+    fn test_overlap() {
+        assert_eq!(
+            FilterCascade::<u32>::build([1, 2, 3], [3, 4, 5]),
+            Err(BuildError::Overlap)
+        );
+    }
+
+    #[test]
+    fn test_encoding() {
+        let include = (0..500u32).collect::<Vec<_>>();
+        let exclude = (500..10_000u32).collect::<Vec<_>>();
+        let cascade = FilterCascade::<u32>::with_rate(&include, &exclude, 0.1).unwrap();
+        let bytes = Vec::from(cascade.clone());
+        let decoded = FilterCascade::<u32>::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(decoded, cascade);
+        assert!(include.iter().all(|i| decoded.contains(i)));
+        assert!(!exclude.iter().any(|i| decoded.contains(i)));
+
+        assert_eq!(
+            FilterCascade::<u32>::try_from(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::UnexpectedEof)
+        );
+        assert_eq!(
+            FilterCascade::<u32>::try_from([bytes.as_slice(), &[0]].concat().as_slice()),
+            Err(DecodeError::TrailingBytes(1))
+        );
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! Compressed Bloom filters, optimized for transmission size.
+//!
+//! As shown by Mitzenmacher in *Compressed Bloom Filters*, the memory-optimal Bloom filter
+//! has half its bits set, and can't be compressed. A larger, sparser filter with fewer
+//! hash functions can reach the same false positive rate while compressing to fewer bits:
+//! a filter of `m` bits with a fraction `p` of bits set compresses to about `m H(p)` bits,
+//! where `H` is the binary entropy function. With a single hash function and a very sparse
+//! filter, this approaches `n (log2(1/ε) + 1.44)` bits, instead of `1.44 n log2(1/ε)`.
+//!
+//! [`plan`] chooses the size and number of hashes of a filter to minimize its compressed
+//! size, and [`encode`] compresses a bit vector with a Golomb-Rice code of the gaps between
+//! set bits, which is close to optimal for sparse filters.
+use crate::bitvec::BitVec;
+use crate::bloom::{self, BloomFilter};
+use crate::encoding::{DecodeError, Reader};
+
+/// Maximum size of a planned filter, as a multiple of the memory-optimal size.
+pub const MAX_EXPANSION: usize = 16;
+
+/// Ratio between successive filter sizes tried by the planner.
+const PLAN_STEP: f64 = 1.02;
+
+/// A filter configuration chosen by [`plan`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plan {
+    /// Size of the filter in memory, in bits.
+    pub nbits: usize,
+    /// Number of hashes.
+    pub nhashes: usize,
+    /// Expected false positive rate at capacity.
+    pub fp_rate: f64,
+    /// Expected size of the filter at capacity once compressed, in bits.
+    pub compressed_bits: usize,
+}
+
+impl Plan {
+    /// Return an empty Bloom filter with the planned size and number of hashes.
+    pub fn build<K>(&self) -> BloomFilter<K> {
+        BloomFilter::from_parts(BitVec::new(self.nbits), self.nhashes)
+    }
+}
+
+/// Plan a filter for the given capacity and false positive rate, with the smallest
+/// expected compressed size, and no larger in memory than [`MAX_EXPANSION`] times the
+/// memory-optimal filter. Returns `None` if the compressed size exceeds the given wire
+/// budget, in bits.
+///
+/// # Panics
+///
+/// Panics if the capacity is zero, or the false positive rate is not strictly between
+/// `0` and `1`.
+pub fn plan(capacity: usize, fp_rate: f64, wire_bits: usize) -> Option<Plan> {
+    assert!(capacity > 0, "capacity must be greater than zero");
+    assert!(
+        fp_rate > 0. && fp_rate < 1.,
+        "false positive rate must be between 0 and 1, got {}",
+        fp_rate
+    );
+    let optimal = bloom::optimal_bits(capacity, fp_rate).max(1);
+    let max_hashes = bloom::optimal_hashes(optimal, capacity).max(1);
+    let n = capacity as f64;
+    let mut best: Option<Plan> = None;
+    let mut m = optimal as f64;
+
+    while m <= (optimal * MAX_EXPANSION) as f64 {
+        let nbits = m.ceil() as usize;
+
+        for nhashes in 1..=max_hashes {
+            let fill = 1. - (-(nhashes as f64) * n / nbits as f64).exp();
+            let rate = fill.powi(nhashes as i32);
+
+            if rate > fp_rate {
+                continue;
+            }
+            let compressed_bits = (nbits as f64 * entropy(fill)).ceil() as usize;
+
+            if best.is_none_or(|b| compressed_bits < b.compressed_bits) {
+                best = Some(Plan {
+                    nbits,
+                    nhashes,
+                    fp_rate: rate,
+                    compressed_bits,
+                });
+            }
+        }
+        m *= PLAN_STEP;
+    }
+    best.filter(|p| p.compressed_bits <= wire_bits)
+}
+
+/// Compress a Bloom filter, encoding its number of hashes followed by its bit vector.
+pub fn compress<K>(filter: &BloomFilter<K>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    bytes.extend_from_slice(&(filter.hashes() as u32).to_le_bytes());
+    bytes.extend_from_slice(&encode(filter.bit_vec()));
+    bytes
+}
+
+/// Decompress a Bloom filter compressed with [`compress`], of at most `max_bits` bits.
+pub fn decompress<K>(bytes: &[u8], max_bits: usize) -> Result<BloomFilter<K>, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let nhashes = reader.u32()? as usize;
+
+    if nhashes == 0 {
+        return Err(DecodeError::InvalidField("nhashes"));
+    }
+    let bits = decode(reader.rest(), max_bits)?;
+
+    Ok(BloomFilter::from_parts(bits, nhashes))
+}
+
+/// Encode a bit vector with a Golomb-Rice code. The encoding is its length, number of set
+/// bits and Rice parameter `b`, followed by the gap before each set bit: the gap divided by
+/// `2^b` in unary, then its `b` low bits.
+pub fn encode(bits: &BitVec) -> Vec<u8> {
+    let mut gaps = Vec::new();
+    let mut next = 0;
+
+    for i in (0..bits.len()).filter(|i| bits.is_set(*i)) {
+        gaps.push((i - next) as u64);
+        next = i + 1;
+    }
+    // Pick the parameter that minimizes the encoded size.
+    let parameter = (0..64u32)
+        .min_by_key(|b| gaps.iter().map(|g| (g >> b) + 1 + *b as u64).sum::<u64>())
+        .unwrap_or(0);
+
+    let mut bytes = Vec::new();
+
+    bytes.extend_from_slice(&(bits.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&(gaps.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&parameter.to_le_bytes());
+
+    let mut writer = BitWriter::new(bytes);
+
+    for gap in gaps {
+        for _ in 0..gap >> parameter {
+            writer.write(true);
+        }
+        writer.write(false);
+
+        for j in (0..parameter).rev() {
+            writer.write((gap >> j) & 1 == 1);
+        }
+    }
+    writer.finish()
+}
+
+/// Decode a bit vector encoded with [`encode`], of at most `max_bits` bits.
+///
+/// Runs of zeros compress to almost nothing, so the length of the input doesn't bound the
+/// length of the vector: the limit is checked before the vector is allocated.
+pub fn decode(bytes: &[u8], max_bits: usize) -> Result<BitVec, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let nbits = reader.usize("nbits")?;
+    let ones = reader.usize("ones")?;
+    let parameter = reader.u32()?;
+
+    if nbits > max_bits {
+        return Err(DecodeError::InvalidField("nbits"));
+    }
+    if ones > nbits {
+        return Err(DecodeError::InvalidField("ones"));
+    }
+    if parameter >= 64 {
+        return Err(DecodeError::InvalidField("parameter"));
+    }
+    let mut reader = BitReader::new(reader.rest());
+
+    // Every set bit takes at least `b + 1` bits to encode.
+    if ones > reader.remaining() / (parameter as usize + 1) {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let mut bits = BitVec::new(nbits);
+    let mut next = 0;
+
+    for _ in 0..ones {
+        let mut quotient: u64 = 0;
+
+        while reader.read()? {
+            quotient += 1;
+
+            if quotient > (nbits >> parameter) as u64 {
+                return Err(DecodeError::InvalidField("gap"));
+            }
+        }
+        let mut gap = quotient << parameter;
+
+        for j in (0..parameter).rev() {
+            gap |= (reader.read()? as u64) << j;
+        }
+        let index = usize::try_from(gap)
+            .ok()
+            .and_then(|g| g.checked_add(next))
+            .filter(|i| *i < nbits)
+            .ok_or(DecodeError::InvalidField("gap"))?;
+
+        bits.set(index);
+        next = index + 1;
+    }
+    reader.finish()?;
+
+    Ok(bits)
+}
+
+/// Return the binary entropy of a probability, in bits.
+fn entropy(p: f64) -> f64 {
+    if p <= 0. || p >= 1. {
+        return 0.;
+    }
+    -p * p.log2() - (1. - p) * (1. - p).log2()
+}
+
+/// Writes bits to a byte vector, least significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    offset: u32,
+}
+
+impl BitWriter {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self { bytes, offset: 8 }
+    }
+
+    fn write(&mut self, bit: bool) {
+        if self.offset == 8 {
+            self.bytes.push(0);
+            self.offset = 0;
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 1 << self.offset;
+        }
+        self.offset += 1;
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads bits from a byte slice, least significant bit first.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read(&mut self) -> Result<bool, DecodeError> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let bit = (byte >> (self.position % 8)) & 1 == 1;
+
+        self.position += 1;
+
+        Ok(bit)
+    }
+
+    /// Return the number of bits left to read.
+    fn remaining(&self) -> usize {
+        (self.bytes.len() * 8).saturating_sub(self.position)
+    }
+
+    /// Check that only padding bits are left.
+    fn finish(self) -> Result<(), DecodeError> {
+        let used = self.position.div_ceil(8);
+
+        if used < self.bytes.len() {
+            return Err(DecodeError::TrailingBytes(self.bytes.len() - used));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng::new(5);
+
+        for (nbits, ones) in [
+            (0, 0),
+            (1, 1),
+            (100, 0),
+            (100, 100),
+            (1000, 10),
+            (4096, 2048),
+        ] {
+            let mut bits = BitVec::new(nbits);
+
+            for _ in 0..ones {
+                bits.set(rng.below(nbits as u64) as usize);
+            }
+            let encoded = encode(&bits);
+
+            assert_eq!(decode(&encoded, nbits).unwrap(), bits);
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut bits = BitVec::new(1000);
+        bits.set(10);
+        bits.set(999);
+
+        let encoded = encode(&bits);
+
+        assert_eq!(
+            decode(&encoded[..encoded.len() - 1], 1000),
+            Err(DecodeError::UnexpectedEof)
+        );
+        assert_eq!(
+            decode(&[encoded.as_slice(), &[0]].concat(), 1000),
+            Err(DecodeError::TrailingBytes(1))
+        );
+        assert_eq!(
+            decode(&encoded, 999),
+            Err(DecodeError::InvalidField("nbits"))
+        );
+
+        // Claim a shorter vector than the encoded indices.
+        let mut truncated = encoded.clone();
+        truncated[..8].copy_from_slice(&500u64.to_le_bytes());
+        assert!(decode(&truncated, 1000).is_err());
+
+        // Claim more set bits than the input can hold.
+        let mut inflated = encoded.clone();
+        inflated[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        inflated[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            decode(&inflated, usize::MAX),
+            Err(DecodeError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let plan = plan(10_000, 0.01, usize::MAX).unwrap();
+        let optimal = bloom::optimal_bits(10_000, 0.01);
+
+        assert!(plan.fp_rate <= 0.01);
+        assert!(plan.nbits > optimal);
+        assert!(plan.nhashes < bloom::optimal_hashes(optimal, 10_000));
+        assert!(plan.compressed_bits < optimal * 9 / 10);
+
+        assert_eq!(super::plan(10_000, 0.01, plan.compressed_bits - 1), None);
+    }
+
+    #[test]
+    fn test_wire_size_saving() {
+        let n = 10_000;
+        let plan = plan(n, 0.01, usize::MAX).unwrap();
+        let mut sparse = plan.build::<u32>();
+        let mut optimal = BloomFilter::<u32>::with_rate(n, 0.01);
+
+        for i in 0..n as u32 {
+            sparse.insert(&i);
+            optimal.insert(&i);
+        }
+        let compressed = compress(&sparse);
+        let decompressed = decompress::<u32>(&compressed, plan.nbits).unwrap();
+
+        assert_eq!(decompressed, sparse);
+        assert!(
+            compressed.len() < optimal.as_bytes().len() * 9 / 10,
+            "compressed to {} bytes, against {} uncompressed",
+            compressed.len(),
+            optimal.as_bytes().len()
+        );
+        // Within a few percent of the expected size.
+        assert!(compressed.len() * 8 < plan.compressed_bits * 105 / 100);
+
+        // The optimal filter is half full, and doesn't compress.
+        assert!(compress(&optimal).len() >= optimal.as_bytes().len());
+
+        let trials = 100_000;
+        let false_positives = (n as u32..n as u32 + trials)
+            .filter(|i| decompressed.contains(i))
+            .count();
+        let rate = false_positives as f64 / trials as f64;
+
+        assert!(rate < 0.012, "false positive rate {} is too high", rate);
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A counting Bloom filter, which supports removing items.
+//!
+//! Instead of a single bit, each position in the filter holds a small counter which is
+//! incremented on insert and decremented on removal. Counters are packed into 64-bit words,
+//! and are 4 bits wide by default.
+//!
+//! # Overflow policy
+//!
+//! Counters saturate at their maximum value, eg. `15` for 4-bit counters. Once a counter
+//! saturates, its true value is unknown, so it is never decremented again: removing an item
+//! leaves saturated counters in place. This means a saturated position can never cause a
+//! false negative, at the cost of some positions staying set after all the items that
+//! mapped to them were removed.
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, BloomFilter, DEFAULT_FALSE_POSITIVE_RATE};
+use crate::packed::PackedVec;
+
+/// The default counter width, in bits.
+pub const DEFAULT_COUNTER_WIDTH: u32 = 4;
+
+/// A Bloom filter with packed, saturating counters that keeps track of items of type `K`.
+#[derive(Clone, Debug)]
+pub struct CountingBloomFilter<K> {
+    counters: PackedVec,
+    nhashes: usize,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> CountingBloomFilter<K> {
+    /// Return a new counting Bloom filter with a given approximate item capacity.
+    /// The default false positive probability and counter width are used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new counting Bloom filter with a given approximate item capacity
+    /// and a desired false positive rate.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        Self::with_width(capacity, fp_rate, DEFAULT_COUNTER_WIDTH)
+    }
+
+    /// Return a new counting Bloom filter with a given approximate item capacity,
+    /// false positive rate and counter width in bits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the width is not between `1` and `32`.
+    pub fn with_width(capacity: usize, fp_rate: f64, width: u32) -> Self {
+        let ncounters = bloom::optimal_bits(capacity, fp_rate).max(1);
+        let nhashes = bloom::optimal_hashes(ncounters, capacity).max(1);
+
+        Self {
+            counters: PackedVec::new(ncounters, width),
+            nhashes,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Add an item to the filter, incrementing its counters. Counters that are already
+    /// saturated are left as-is.
+    pub fn insert(&mut self, item: &K) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        let max = self.counters.max();
+
+        for i in 0..self.nhashes {
+            let index = self.index(h1, h2, i);
+            let value = self.counters.get(index);
+
+            if value < max {
+                self.counters.put(index, value + 1);
+            }
+        }
+    }
+
+    /// Remove an item from the filter, decrementing its counters. Saturated counters are
+    /// never decremented.
+    ///
+    /// Returns `false` and leaves the filter untouched if the item is definitely not in the
+    /// filter. Removing an item that was never inserted, but is a false positive, may
+    /// cause false negatives for other items.
+    pub fn remove(&mut self, item: &K) -> bool {
+        if !self.contains(item) {
+            return false;
+        }
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        let max = self.counters.max();
+
+        for i in 0..self.nhashes {
+            let index = self.index(h1, h2, i);
+            let value = self.counters.get(index);
+
+            // Since an index can repeat for a single item, it may already have been
+            // decremented to zero.
+            if value > 0 && value < max {
+                self.counters.put(index, value - 1);
+            }
+        }
+        true
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        self.estimate_count(item) > 0
+    }
+
+    /// Estimate how many times an item was inserted. This is the smallest of the item's
+    /// counters, and is never lower than the true count unless the estimate saturates.
+    pub fn estimate_count(&self, item: &K) -> usize {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        (0..self.nhashes)
+            .map(|i| self.counters.get(self.index(h1, h2, i)))
+            .min()
+            .unwrap_or(0) as usize
+    }
+}
+
+impl<K> CountingBloomFilter<K> {
+    /// Set all counters to zero.
+    pub fn clear(&mut self) {
+        self.counters.clear();
+    }
+
+    /// Return the number of counters in this filter.
+    pub fn counters(&self) -> usize {
+        self.counters.len()
+    }
+
+    /// Return the width of each counter, in bits.
+    pub fn counter_width(&self) -> u32 {
+        self.counters.width()
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Return the number of counters that have saturated.
+    pub fn saturated(&self) -> usize {
+        let max = self.counters.max();
+        (0..self.counters.len())
+            .filter(|i| self.counters.get(*i) == max)
+            .count()
+    }
+
+    /// Return a plain Bloom filter with a bit set wherever a counter is non-zero.
+    /// The resulting filter answers `contains` exactly like this one.
+    pub fn to_bloom_filter(&self) -> BloomFilter<K> {
+        let mut bits = BitVec::new(self.counters.len());
+
+        for i in 0..self.counters.len() {
+            if self.counters.get(i) > 0 {
+                bits.set(i);
+            }
+        }
+        BloomFilter::from_parts(bits, self.nhashes)
+    }
+
+    fn index(&self, h1: u64, h2: u64, i: usize) -> usize {
+        bloom::bloom_hash(h1, h2, i as u64, self.counters.len() as u64) as usize
+    }
+}
+
+impl<K> From<CountingBloomFilter<K>> for BloomFilter<K> {
+    fn from(other: CountingBloomFilter<K>) -> Self {
+        other.to_bloom_filter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut cbf = CountingBloomFilter::<u32>::new(1024);
+
+        for i in 0..512 {
+            cbf.insert(&i);
+        }
+        for i in 0..512 {
+            assert!(cbf.contains(&i), "item {} resulted in a false negative", i);
+        }
+        for i in 0..256 {
+            assert!(cbf.remove(&i));
+        }
+        for i in 256..512 {
+            assert!(cbf.contains(&i), "item {} resulted in a false negative", i);
+        }
+        let false_positives = (0..256).filter(|i| cbf.contains(i)).count();
+        assert!(false_positives < 16);
+    }
+
+    #[test]
+    fn test_remove_absent() {
+        let mut cbf = CountingBloomFilter::<&str>::new(32);
+
+        cbf.insert(&"foo");
+        assert!(!cbf.remove(&"bar"));
+        assert!(cbf.contains(&"foo"));
+        assert!(cbf.remove(&"foo"));
+        assert!(!cbf.contains(&"foo"));
+    }
+
+    #[test]
+    fn test_estimate_count() {
+        let mut cbf = CountingBloomFilter::<&str>::new(32);
+
+        for _ in 0..3 {
+            cbf.insert(&"foo");
+        }
+        cbf.insert(&"bar");
+
+        assert_eq!(cbf.estimate_count(&"foo"), 3);
+        assert_eq!(cbf.estimate_count(&"bar"), 1);
+        assert_eq!(cbf.estimate_count(&"baz"), 0);
+    }
+
+    #[test]
+    fn test_saturation() {
+        let mut cbf = CountingBloomFilter::<&str>::with_width(32, 0.01, 2);
+
+        for _ in 0..5 {
+            cbf.insert(&"foo");
+        }
+        assert_eq!(cbf.estimate_count(&"foo"), 3);
+        assert!(cbf.saturated() > 0);
+
+        // Saturated counters are sticky, so the item can't be removed entirely.
+        for _ in 0..5 {
+            cbf.remove(&"foo");
+        }
+        assert!(cbf.contains(&"foo"));
+    }
+
+    #[test]
+    fn test_to_bloom_filter() {
+        let mut cbf = CountingBloomFilter::<u32>::new(256);
+        let mut bf = BloomFilter::<u32>::new(256);
+
+        for i in 0..128 {
+            cbf.insert(&i);
+            bf.insert(&i);
+        }
+        let converted = BloomFilter::from(cbf.clone());
+
+        assert_eq!(converted, bf);
+        assert!(converted.is_comparable(&bf));
+        for i in 0..1024 {
+            assert_eq!(converted.contains(&i), cbf.contains(&i));
+        }
+    }
+
+    #[test]
+    fn test_tiny() {
+        // Fewer counters than items, for which the optimal number of hashes is zero.
+        for capacity in [0, 100] {
+            let mut cbf = CountingBloomFilter::<u32>::with_rate(capacity, 0.9);
+
+            assert_eq!(cbf.hashes(), 1);
+            cbf.insert(&1);
+            assert!(cbf.contains(&1));
+            assert_eq!(cbf.estimate_count(&1), 1);
+            assert!(cbf.to_bloom_filter().contains(&1));
+        }
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A count-min sketch, for estimating how many times an item was seen.
+//!
+//! As described by Cormode and Muthukrishnan in *An Improved Data Stream Summary: The
+//! Count-Min Sketch and its Applications*, the sketch is a matrix of `d` rows of `w`
+//! counters. Each row has its own hash function, derived with the same enhanced double
+//! hashing as [`BloomFilter`](crate::BloomFilter). Incrementing an item increments one
+//! counter per row, and the estimate of an item is the minimum of its counters.
+//!
+//! Estimates never undercount. With `w = ⌈e/ε⌉` and `d = ⌈ln(1/δ)⌉`, an estimate exceeds
+//! the true count by more than `εN` with probability at most `δ`, where `N` is the total
+//! of all increments.
+//!
+//! # Conservative update
+//!
+//! In conservative-update mode, an increment only raises the counters of an item up to
+//! its new estimate, leaving counters that are already higher untouched. This can greatly
+//! reduce overcounting on skewed streams, with the same guarantees, but the counters no
+//! longer add up: such sketches can still be merged, but the merged estimates are looser.
+use std::f64;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom;
+
+/// A count-min sketch that estimates the frequency of items of type `K`.
+#[derive(Debug)]
+pub struct CountMinSketch<K> {
+    /// Counters, row by row.
+    counters: Vec<u64>,
+    width: usize,
+    depth: usize,
+    total: u64,
+    conservative: bool,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> CountMinSketch<K> {
+    /// Return a new count-min sketch whose estimates are within `εN` of the true counts,
+    /// with probability `1 - δ`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ε` or `δ` are not strictly between `0` and `1`.
+    pub fn new(epsilon: f64, delta: f64) -> Self {
+        assert!(
+            epsilon > 0. && epsilon < 1.,
+            "epsilon must be between 0 and 1, got {}",
+            epsilon
+        );
+        assert!(
+            delta > 0. && delta < 1.,
+            "delta must be between 0 and 1, got {}",
+            delta
+        );
+        let width = (f64::consts::E / epsilon).ceil() as usize;
+        let depth = (1. / delta).ln().ceil().max(1.) as usize;
+
+        Self::with_dimensions(width, depth)
+    }
+
+    /// Return a new count-min sketch with the same error bounds as [`CountMinSketch::new`],
+    /// in conservative-update mode.
+    pub fn with_conservative_update(epsilon: f64, delta: f64) -> Self {
+        Self {
+            conservative: true,
+            ..Self::new(epsilon, delta)
+        }
+    }
+
+    /// Return a new count-min sketch with `d` rows of `w` counters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either dimension is zero.
+    pub fn with_dimensions(width: usize, depth: usize) -> Self {
+        assert!(
+            width > 0 && depth > 0,
+            "sketch dimensions must be greater than zero, got {}x{}",
+            width,
+            depth
+        );
+        Self {
+            counters: vec![0; width * depth],
+            width,
+            depth,
+            total: 0,
+            conservative: false,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Add `by` to the count of an item. Counters saturate at `u64::MAX`.
+    pub fn increment(&mut self, item: &K, by: u64) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        self.total = self.total.saturating_add(by);
+
+        if self.conservative {
+            let target = self.min(h1, h2).saturating_add(by);
+
+            for row in 0..self.depth {
+                let i = self.index(h1, h2, row);
+                self.counters[i] = self.counters[i].max(target);
+            }
+        } else {
+            for row in 0..self.depth {
+                let i = self.index(h1, h2, row);
+                self.counters[i] = self.counters[i].saturating_add(by);
+            }
+        }
+    }
+
+    /// Return an estimate of the count of an item. The estimate is never lower than the
+    /// true count.
+    pub fn estimate(&self, item: &K) -> u64 {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        self.min(h1, h2)
+    }
+
+    /// Return the minimum of an item's counters.
+    fn min(&self, h1: u64, h2: u64) -> u64 {
+        (0..self.depth)
+            .map(|row| self.counters[self.index(h1, h2, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn index(&self, h1: u64, h2: u64, row: usize) -> usize {
+        row * self.width + bloom::bloom_hash(h1, h2, row as u64, self.width as u64) as usize
+    }
+}
+
+impl<K> CountMinSketch<K> {
+    /// Return the number of counters per row, `w`.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Return the number of rows, `d`.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Return the total of all increments, `N`.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Return the error factor `ε = e/w`: estimates are likely within `εN` of the true
+    /// counts.
+    pub fn epsilon(&self) -> f64 {
+        f64::consts::E / self.width as f64
+    }
+
+    /// Return the probability `δ = e^-d` that an estimate exceeds the error bound.
+    pub fn delta(&self) -> f64 {
+        (-(self.depth as f64)).exp()
+    }
+
+    /// Check whether the sketch is in conservative-update mode.
+    pub fn is_conservative(&self) -> bool {
+        self.conservative
+    }
+
+    /// Reset all counters to zero.
+    pub fn clear(&mut self) {
+        self.counters.iter_mut().for_each(|c| *c = 0);
+        self.total = 0;
+    }
+
+    /// Check whether two sketches have the same dimensions and hash functions, and can
+    /// be merged or compared.
+    pub fn is_comparable(&self, other: &Self) -> bool {
+        self.width == other.width
+            && self.depth == other.depth
+            && self.hashers[0].keys() == other.hashers[0].keys()
+            && self.hashers[1].keys() == other.hashers[1].keys()
+    }
+
+    /// Return a sketch of the combined streams of two comparable sketches, by adding their
+    /// counters. The result is in conservative-update mode if either sketch is.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sketches are not comparable.
+    pub fn merge(&self, other: &Self) -> Self {
+        assert!(
+            self.is_comparable(other),
+            "unable to merge sketches with different configurations"
+        );
+        let counters = self
+            .counters
+            .iter()
+            .zip(&other.counters)
+            .map(|(a, b)| a.saturating_add(*b))
+            .collect();
+
+        Self {
+            counters,
+            width: self.width,
+            depth: self.depth,
+            total: self.total.saturating_add(other.total),
+            conservative: self.conservative || other.conservative,
+            hashers: self.hashers,
+            key: PhantomData,
+        }
+    }
+
+    /// Return an estimate of the inner product of the two streams summarized by comparable
+    /// sketches, `Σ f(x)g(x)`, eg. the size of an equi-join. The estimate is never lower
+    /// than the true product, and likely within `εNM`, where `N` and `M` are the totals of
+    /// the two sketches.
+    ///
+    /// Conservative updates leave counters below the sum of the counts hashed to them, so
+    /// if either sketch uses them, the estimate may also be lower than the true product.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sketches are not comparable.
+    pub fn inner_product(&self, other: &Self) -> u64 {
+        assert!(
+            self.is_comparable(other),
+            "unable to compare sketches with different configurations"
+        );
+        self.counters
+            .chunks_exact(self.width)
+            .zip(other.counters.chunks_exact(other.width))
+            .map(|(a, b)| {
+                a.iter()
+                    .zip(b)
+                    .fold(0u64, |sum, (x, y)| sum.saturating_add(x.saturating_mul(*y)))
+            })
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+impl<K> Clone for CountMinSketch<K> {
+    fn clone(&self) -> Self {
+        Self {
+            counters: self.counters.clone(),
+            width: self.width,
+            depth: self.depth,
+            total: self.total,
+            conservative: self.conservative,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A skewed stream, where item `i` is seen `1000 / (i + 1)` times.
+    fn stream() -> impl Iterator<Item = (u32, u64)> {
+        (0..1000u32).map(|i| (i, 1000 / (i as u64 + 1)))
+    }
+
+    #[test]
+    fn test_dimensions() {
+        let cms = CountMinSketch::<u32>::new(0.001, 0.01);
+
+        assert_eq!(cms.width(), 2719);
+        assert_eq!(cms.depth(), 5);
+        assert!(cms.epsilon() <= 0.001);
+        assert!(cms.delta() <= 0.01);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let mut cms = CountMinSketch::<u32>::new(0.001, 0.01);
+
+        for (item, count) in stream() {
+            cms.increment(&item, count);
+        }
+        let bound = (cms.epsilon() * cms.total() as f64) as u64;
+        let mut exceeded = 0;
+
+        for (item, count) in stream() {
+            let estimate = cms.estimate(&item);
+
+            assert!(estimate >= count, "item {} was undercounted", item);
+            if estimate > count + bound {
+                exceeded += 1;
+            }
+        }
+        assert!(exceeded <= 10, "{} estimates exceeded the bound", exceeded);
+        assert_eq!(cms.estimate(&1000), 0);
+    }
+
+    #[test]
+    fn test_conservative_update() {
+        let mut plain = CountMinSketch::<u32>::new(0.05, 0.02);
+        let mut conservative = CountMinSketch::<u32>::with_conservative_update(0.05, 0.02);
+
+        assert!(conservative.is_conservative());
+        assert_eq!(
+            (plain.width(), plain.depth()),
+            (conservative.width(), conservative.depth())
+        );
+
+        for (item, count) in stream() {
+            for _ in 0..count {
+                plain.increment(&item, 1);
+                conservative.increment(&item, 1);
+            }
+        }
+        let error = |cms: &CountMinSketch<u32>| -> u64 {
+            stream()
+                .map(|(item, count)| {
+                    let estimate = cms.estimate(&item);
+                    assert!(estimate >= count, "item {} was undercounted", item);
+                    estimate - count
+                })
+                .sum()
+        };
+        assert_eq!(plain.total(), conservative.total());
+        assert!(error(&conservative) < error(&plain));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = CountMinSketch::<u32>::new(0.01, 0.01);
+        let mut b = CountMinSketch::<u32>::new(0.01, 0.01);
+        let mut both = CountMinSketch::<u32>::new(0.01, 0.01);
+
+        for (item, count) in stream() {
+            if item % 2 == 0 {
+                a.increment(&item, count);
+            } else {
+                b.increment(&item, count);
+            }
+            both.increment(&item, count);
+        }
+        let merged = a.merge(&b);
+
+        assert_eq!(merged.total(), both.total());
+        for (item, _) in stream() {
+            assert_eq!(merged.estimate(&item), both.estimate(&item));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_merge_incompatible() {
+        let a = CountMinSketch::<u32>::new(0.01, 0.01);
+        let b = CountMinSketch::<u32>::new(0.001, 0.01);
+
+        a.merge(&b);
+    }
+
+    #[test]
+    fn test_inner_product() {
+        let mut a = CountMinSketch::<u32>::new(0.001, 0.01);
+        let mut b = CountMinSketch::<u32>::new(0.001, 0.01);
+        let mut expected = 0;
+
+        for (item, count) in stream() {
+            a.increment(&item, count);
+            if item % 3 == 0 {
+                b.increment(&item, 2);
+                expected += count * 2;
+            }
+        }
+        let product = a.inner_product(&b);
+        let bound = (a.epsilon() * (a.total() * b.total()) as f64) as u64;
+
+        assert!(product >= expected);
+        assert!(product <= expected + bound);
+    }
+
+    #[test]
+    fn test_inner_product_conservative() {
+        let mut a = CountMinSketch::<u32>::with_conservative_update(0.3, 0.05);
+        let mut expected = 0;
+
+        for item in 0..20u32 {
+            let count = u64::from(item % 5 + 1);
+            a.increment(&item, count);
+            expected += count * count;
+        }
+        let b = a.clone();
+
+        assert!(a.is_conservative());
+        assert!(a.inner_product(&b) < expected);
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A cuckoo filter, which supports removing items and is smaller than a Bloom filter for
+//! low false positive rates.
+//!
+//! As described by Fan, Andersen, Kaminsky and Mitzenmacher in *Cuckoo Filter: Practically
+//! Better Than Bloom*, the filter stores a short fingerprint of each item in one of two
+//! candidate buckets. With *partial-key cuckoo hashing*, the alternate bucket of a
+//! fingerprint is derived from the fingerprint alone, `i2 = i1 ⊕ hash(f)`, so fingerprints
+//! can be moved between buckets without knowing the original item. When both buckets are
+//! full, a random fingerprint is evicted to its alternate bucket, and so on.
+//!
+//! Unlike a Bloom filter, a cuckoo filter can become full: inserting into a full filter
+//! returns an error and leaves the filter unchanged.
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom::{self, DEFAULT_FALSE_POSITIVE_RATE};
+use crate::encoding::{DecodeError, Reader};
+use crate::packed::PackedVec;
+use crate::rng::Rng;
+
+/// The default number of fingerprints per bucket.
+pub const DEFAULT_BUCKET_SIZE: usize = 4;
+
+/// Maximum number of evictions before an insert fails.
+const MAX_KICKS: usize = 500;
+
+/// Maximum load factor used when sizing a filter for a given capacity.
+const MAX_LOAD_FACTOR: f64 = 0.95;
+
+/// Seed of the random number generator used to pick fingerprints to evict.
+const RNG_SEED: u64 = 0x2f6b_31c9_d7a8_5e04;
+
+/// An error inserting an item into a filter with a fixed number of slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertError {
+    /// The filter is too full to make room for the item.
+    Full,
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "filter is full"),
+        }
+    }
+}
+
+impl std::error::Error for InsertError {}
+
+/// A cuckoo filter that keeps track of items of type `K`.
+#[derive(Debug)]
+pub struct CuckooFilter<K> {
+    /// Fingerprint slots, bucket by bucket. Zero marks an empty slot.
+    slots: PackedVec,
+    nbuckets: usize,
+    bucket_size: usize,
+    len: usize,
+    rng: Rng,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> CuckooFilter<K> {
+    /// Return a new cuckoo filter with a given approximate item capacity.
+    /// The default false positive probability is used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new cuckoo filter with a given approximate item capacity and a desired
+    /// false positive rate. The fingerprint size is chosen so that `2b / 2^f` doesn't
+    /// exceed the rate.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        let bits = ((2. * DEFAULT_BUCKET_SIZE as f64) / fp_rate).log2().ceil() as u32;
+
+        Self::with_params(capacity, bits.clamp(1, 32), DEFAULT_BUCKET_SIZE)
+    }
+
+    /// Return a new cuckoo filter with a given approximate item capacity, fingerprint size
+    /// in bits, and number of fingerprints per bucket. The number of buckets is rounded up
+    /// to a power of two.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the fingerprint size is not between `1` and `32`, or the bucket size is
+    /// zero.
+    pub fn with_params(capacity: usize, fingerprint_bits: u32, bucket_size: usize) -> Self {
+        assert!(bucket_size > 0, "bucket size must be greater than zero");
+
+        let nbuckets = (capacity as f64 / (bucket_size as f64 * MAX_LOAD_FACTOR)).ceil() as usize;
+        let nbuckets = nbuckets.max(1).next_power_of_two();
+
+        Self {
+            slots: PackedVec::new(nbuckets * bucket_size, fingerprint_bits),
+            nbuckets,
+            bucket_size,
+            len: 0,
+            rng: Rng::new(RNG_SEED),
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Add an item to the filter. Inserting the same item more than once stores one
+    /// fingerprint per insert, so that it can be removed as many times.
+    ///
+    /// Returns [`InsertError::Full`] if no room could be made for the item, in which case
+    /// the filter is left unchanged.
+    pub fn insert(&mut self, item: &K) -> Result<(), InsertError> {
+        let (fingerprint, i1) = self.fingerprint(item);
+        let i2 = self.alternate(i1, fingerprint);
+
+        if self.put(i1, fingerprint) || self.put(i2, fingerprint) {
+            self.len += 1;
+            return Ok(());
+        }
+        // Both buckets are full: evict fingerprints until one finds an empty slot.
+        let mut bucket = if self.rng.below(2) == 0 { i1 } else { i2 };
+        let mut fingerprint = fingerprint;
+        let mut path = Vec::with_capacity(MAX_KICKS);
+
+        for _ in 0..MAX_KICKS {
+            let slot = bucket * self.bucket_size + self.rng.below(self.bucket_size as u64) as usize;
+            let victim = self.slots.get(slot);
+
+            self.slots.put(slot, fingerprint);
+            path.push((slot, victim));
+
+            fingerprint = victim;
+            bucket = self.alternate(bucket, fingerprint);
+
+            if self.put(bucket, fingerprint) {
+                self.len += 1;
+                return Ok(());
+            }
+        }
+        // Undo the evictions, so that no fingerprint is lost.
+        for (slot, victim) in path.into_iter().rev() {
+            self.slots.put(slot, victim);
+        }
+        Err(InsertError::Full)
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        let (fingerprint, i1) = self.fingerprint(item);
+        let i2 = self.alternate(i1, fingerprint);
+
+        self.find(i1, fingerprint).is_some() || self.find(i2, fingerprint).is_some()
+    }
+
+    /// Remove one copy of an item from the filter. Returns `false` if the item was not
+    /// found.
+    ///
+    /// Only items that were inserted should be removed: removing a false positive removes
+    /// the fingerprint of another item.
+    pub fn remove(&mut self, item: &K) -> bool {
+        let (fingerprint, i1) = self.fingerprint(item);
+        let i2 = self.alternate(i1, fingerprint);
+
+        for bucket in [i1, i2] {
+            if let Some(slot) = self.find(bucket, fingerprint) {
+                self.slots.put(slot, 0);
+                self.len -= 1;
+
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Return the non-zero fingerprint of an item, and its primary bucket.
+    fn fingerprint(&self, item: &K) -> (u64, usize) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        let fingerprint = (h2 & self.slots.max()).max(1);
+        let bucket = (h1 & (self.nbuckets as u64 - 1)) as usize;
+
+        (fingerprint, bucket)
+    }
+}
+
+impl<K> CuckooFilter<K> {
+    /// Return the number of items in the filter.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the filter is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the maximum number of fingerprints the filter can hold. In practice, inserts
+    /// start failing at a load factor of around 95% with four fingerprints per bucket.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Return the fraction of slots that are in use.
+    pub fn load_factor(&self) -> f64 {
+        self.len as f64 / self.slots.len() as f64
+    }
+
+    /// Return the number of buckets.
+    pub fn buckets(&self) -> usize {
+        self.nbuckets
+    }
+
+    /// Return the number of fingerprints per bucket.
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
+    /// Return the size of a fingerprint, in bits.
+    pub fn fingerprint_bits(&self) -> u32 {
+        self.slots.width()
+    }
+
+    /// Return the upper bound on the false positive rate when the filter is full,
+    /// `1 - (1 - 1/(2^f - 1))^2b`, since there are `2^f - 1` non-zero fingerprints.
+    pub fn fp_rate(&self) -> f64 {
+        let f = self.slots.max() as f64;
+        1. - (1. - 1. / f).powi(2 * self.bucket_size as i32)
+    }
+
+    /// Remove all items.
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.len = 0;
+    }
+
+    /// Return the alternate bucket of a fingerprint stored in the given bucket.
+    fn alternate(&self, bucket: usize, fingerprint: u64) -> usize {
+        let mut hasher = self.hashers[0];
+        hasher.write_u64(fingerprint);
+
+        (bucket ^ hasher.finish() as usize) & (self.nbuckets - 1)
+    }
+
+    /// Store a fingerprint in an empty slot of the given bucket, if there is one.
+    fn put(&mut self, bucket: usize, fingerprint: u64) -> bool {
+        let start = bucket * self.bucket_size;
+
+        for slot in start..start + self.bucket_size {
+            if self.slots.get(slot) == 0 {
+                self.slots.put(slot, fingerprint);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Return the slot holding a fingerprint in the given bucket.
+    fn find(&self, bucket: usize, fingerprint: u64) -> Option<usize> {
+        let start = bucket * self.bucket_size;
+        (start..start + self.bucket_size).find(|slot| self.slots.get(*slot) == fingerprint)
+    }
+}
+
+impl<K> Clone for CuckooFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            slots: self.slots.clone(),
+            nbuckets: self.nbuckets,
+            bucket_size: self.bucket_size,
+            len: self.len,
+            rng: self.rng.clone(),
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+impl<K> From<CuckooFilter<K>> for Vec<u8> {
+    /// Encode the filter as its fingerprint size, bucket size, number of buckets and
+    /// items, followed by the packed fingerprints.
+    fn from(other: CuckooFilter<K>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&other.slots.width().to_le_bytes());
+        bytes.extend_from_slice(&(other.bucket_size as u32).to_le_bytes());
+        bytes.extend_from_slice(&(other.nbuckets as u64).to_le_bytes());
+        bytes.extend_from_slice(&(other.len as u64).to_le_bytes());
+
+        for word in other.slots.words() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+impl<K> TryFrom<&[u8]> for CuckooFilter<K> {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let width = reader.u32()?;
+        let bucket_size = reader.u32()? as usize;
+        let nbuckets = reader.usize("buckets")?;
+        let len = reader.usize("len")?;
+
+        if !(1..=32).contains(&width) {
+            return Err(DecodeError::InvalidField("fingerprint_bits"));
+        }
+        if bucket_size == 0 {
+            return Err(DecodeError::InvalidField("bucket_size"));
+        }
+        if !nbuckets.is_power_of_two() {
+            return Err(DecodeError::InvalidField("buckets"));
+        }
+        let nslots = nbuckets
+            .checked_mul(bucket_size)
+            .ok_or(DecodeError::InvalidField("buckets"))?;
+        let nwords = PackedVec::words_for(nslots, width);
+        let words = reader
+            .bytes(nwords.checked_mul(8).ok_or(DecodeError::UnexpectedEof)?)?
+            .chunks_exact(8)
+            .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+        let slots = PackedVec::from_words(words, nslots, width)
+            .ok_or(DecodeError::InvalidField("slots"))?;
+
+        reader.finish()?;
+
+        if len != (0..nslots).filter(|i| slots.get(*i) != 0).count() {
+            return Err(DecodeError::InvalidField("len"));
+        }
+        Ok(Self {
+            slots,
+            nbuckets,
+            bucket_size,
+            len,
+            rng: Rng::new(RNG_SEED),
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_contains_remove() {
+        let mut cf = CuckooFilter::<u32>::new(1000);
+
+        for i in 0..1000 {
+            cf.insert(&i).unwrap();
+        }
+        assert_eq!(cf.len(), 1000);
+
+        for i in 0..1000 {
+            assert!(cf.contains(&i), "item {} resulted in a false negative", i);
+        }
+        for i in 0..500 {
+            assert!(cf.remove(&i));
+        }
+        assert_eq!(cf.len(), 500);
+
+        for i in 500..1000 {
+            assert!(cf.contains(&i), "item {} resulted in a false negative", i);
+        }
+    }
+
+    #[test]
+    fn test_fp_rate() {
+        let mut cf = CuckooFilter::<u32>::with_rate(10_000, 0.01);
+
+        for i in 0..10_000 {
+            cf.insert(&i).unwrap();
+        }
+        let trials = 100_000;
+        let false_positives = (10_000..10_000 + trials).filter(|i| cf.contains(i)).count();
+        let rate = false_positives as f64 / trials as f64;
+
+        assert!(cf.fp_rate() <= 0.01);
+        assert!(rate < 0.01, "false positive rate {} is too high", rate);
+    }
+
+    #[test]
+    fn test_full() {
+        let mut cf = CuckooFilter::<u32>::with_params(64, 16, 4);
+        let mut inserted = Vec::new();
+
+        let err = loop {
+            let i = inserted.len() as u32;
+
+            match cf.insert(&i) {
+                Ok(()) => inserted.push(i),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, InsertError::Full);
+        assert_eq!(cf.len(), inserted.len());
+        assert!(cf.load_factor() > 0.8);
+
+        // Nothing was dropped by the failed insert.
+        for i in &inserted {
+            assert!(cf.contains(i), "item {} was dropped", i);
+        }
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut cf = CuckooFilter::<&str>::new(100);
+
+        cf.insert(&"foo").unwrap();
+        cf.insert(&"foo").unwrap();
+        assert_eq!(cf.len(), 2);
+
+        assert!(cf.remove(&"foo"));
+        assert!(cf.contains(&"foo"));
+        assert!(cf.remove(&"foo"));
+        assert!(!cf.contains(&"foo"));
+        assert!(!cf.remove(&"foo"));
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut a = CuckooFilter::<u32>::with_params(500, 12, 4);
+
+        for i in 0..500 {
+            a.insert(&i).unwrap();
+        }
+        let bytes: Vec<u8> = a.clone().into();
+        let mut b = CuckooFilter::<u32>::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(b.len(), 500);
+        assert_eq!(b.fingerprint_bits(), 12);
+        for i in 0..500 {
+            assert!(b.contains(&i));
+        }
+        assert!(b.remove(&0));
+        assert!(CuckooFilter::<u32>::try_from(&bytes[..10]).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A deletable Bloom filter, which supports removing most items for a small overhead.
+//!
+//! As described by Rothenberg, Macapuna, Verdi and Magalhães in *The Deletable Bloom
+//! Filter: A New Member of the Bloom Family*, the bit vector is divided into regions of a
+//! few bits, and a collision bitmap records which regions had a bit set by more than one
+//! insert. Bits in collision-free regions belong to a single item, so they can be safely
+//! reset when that item is removed. An item can be removed as long as at least one of its
+//! bits is in a collision-free region; otherwise, it stays in the filter.
+//!
+//! With the default region size of `4` bits, the collision bitmap adds a quarter to the size
+//! of the filter, and close to 90% of items are deletable when the filter is at capacity.
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, BloomFilter, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// The default number of bits per region.
+pub const DEFAULT_REGION_SIZE: usize = 4;
+
+/// A Bloom filter that keeps track of items of type `K`, and supports removing them.
+#[derive(Debug)]
+pub struct DeletableBloomFilter<K> {
+    bits: BitVec,
+    /// One bit per region, set if the region ever had a collision.
+    collisions: BitVec,
+    region_size: usize,
+    nhashes: usize,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+/// Statistics on the deletability of items in a [`DeletableBloomFilter`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    /// Number of regions.
+    pub regions: usize,
+    /// Number of regions that had a collision.
+    pub collided_regions: usize,
+    /// Fraction of bits that are set.
+    pub fill_ratio: f64,
+    /// Estimated fraction of items that can be removed, ie. the probability that at least
+    /// one of an item's `k` bits is in a collision-free region.
+    pub deletable_rate: f64,
+}
+
+impl<K: Hash> DeletableBloomFilter<K> {
+    /// Return a new deletable Bloom filter with a given approximate item capacity.
+    /// The default false positive probability and region size are used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new deletable Bloom filter with a given approximate item capacity
+    /// and a desired false positive rate.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        Self::with_region_size(capacity, fp_rate, DEFAULT_REGION_SIZE)
+    }
+
+    /// Return a new deletable Bloom filter with a given approximate item capacity,
+    /// false positive rate and region size in bits. Smaller regions make more items
+    /// deletable, at the cost of a larger collision bitmap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region size is zero.
+    pub fn with_region_size(capacity: usize, fp_rate: f64, region_size: usize) -> Self {
+        assert!(region_size > 0, "region size must be greater than zero");
+
+        let nbits = bloom::optimal_bits(capacity, fp_rate);
+        let nhashes = bloom::optimal_hashes(nbits, capacity);
+
+        Self {
+            bits: BitVec::new(nbits),
+            collisions: BitVec::new(nbits.div_ceil(region_size)),
+            region_size,
+            nhashes,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Add an item to the filter. Setting a bit that is already set marks its region as
+    /// collided.
+    pub fn insert(&mut self, item: &K) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        for i in 0..self.nhashes {
+            let index = self.index(h1, h2, i);
+
+            if self.bits.is_set(index) {
+                self.collisions.set(index / self.region_size);
+            } else {
+                self.bits.set(index);
+            }
+        }
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        (0..self.nhashes).all(|i| self.bits.is_set(self.index(h1, h2, i)))
+    }
+
+    /// Remove an item from the filter, by resetting its bits in collision-free regions.
+    /// Returns `true` if at least one bit was reset, and the item is no longer in the
+    /// filter. Returns `false` if the item was not found, or if all its bits are in
+    /// collided regions, in which case the filter is left unchanged.
+    ///
+    /// Only items that were inserted should be removed: removing a false positive may
+    /// remove another item.
+    pub fn remove(&mut self, item: &K) -> bool {
+        if !self.contains(item) {
+            return false;
+        }
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        let mut removed = false;
+
+        for i in 0..self.nhashes {
+            let index = self.index(h1, h2, i);
+
+            if !self.collisions.is_set(index / self.region_size) {
+                self.bits.unset(index);
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    fn index(&self, h1: u64, h2: u64, i: usize) -> usize {
+        bloom::bloom_hash(h1, h2, i as u64, self.bits.len() as u64) as usize
+    }
+}
+
+impl<K> DeletableBloomFilter<K> {
+    /// Return the number of bits in this filter, excluding the collision bitmap.
+    pub fn bits(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Return the number of bits per region.
+    pub fn region_size(&self) -> usize {
+        self.region_size
+    }
+
+    /// Return the number of regions, ie. the size of the collision bitmap.
+    pub fn regions(&self) -> usize {
+        self.collisions.len()
+    }
+
+    /// Return statistics on the deletability of items.
+    ///
+    /// The deletable rate is estimated from the fill ratio `f`: each bit was set by a
+    /// number of inserts following a Poisson distribution of mean `λ = -ln(1 - f)`. One of
+    /// an item's bits is in a collision-free region if no other insert set that bit, with
+    /// probability `e^-λ`, and no other bit of the region was set twice, with probability
+    /// `(e^-λ (1 + λ))^(s - 1)` for regions of `s` bits. The item is deletable unless all
+    /// of its `k` bits are in collided regions.
+    pub fn stats(&self) -> Stats {
+        let fill_ratio = self.bits.count_ones() as f64 / self.bits.len() as f64;
+        let lambda = -(1. - fill_ratio).ln();
+        let single = (-lambda).exp();
+        let free = single * (single * (1. + lambda)).powi(self.region_size as i32 - 1);
+
+        Stats {
+            regions: self.collisions.len(),
+            collided_regions: self.collisions.count_ones(),
+            fill_ratio,
+            deletable_rate: 1. - (1. - free).powi(self.nhashes as i32),
+        }
+    }
+
+    /// Set all bits to zero, and reset the collision bitmap.
+    pub fn clear(&mut self) {
+        self.bits.clear();
+        self.collisions.clear();
+    }
+
+    /// Return a plain Bloom filter with the same bits, dropping the collision bitmap.
+    pub fn to_bloom_filter(&self) -> BloomFilter<K> {
+        BloomFilter::from_parts(self.bits.clone(), self.nhashes)
+    }
+}
+
+impl<K> Clone for DeletableBloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            bits: self.bits.clone(),
+            collisions: self.collisions.clone(),
+            region_size: self.region_size,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+impl<K> From<DeletableBloomFilter<K>> for BloomFilter<K> {
+    fn from(other: DeletableBloomFilter<K>) -> Self {
+        BloomFilter::from_parts(other.bits, other.nhashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut dbf = DeletableBloomFilter::<u32>::new(1000);
+
+        for i in 0..1000 {
+            dbf.insert(&i);
+        }
+        let removed = (0..500).filter(|i| dbf.remove(i)).collect::<Vec<_>>();
+
+        for i in &removed {
+            assert!(!dbf.contains(i), "item {} was not removed", i);
+        }
+        for i in 500..1000 {
+            assert!(dbf.contains(&i), "item {} resulted in a false negative", i);
+        }
+        assert!(
+            removed.len() > 400,
+            "only {} items were deletable",
+            removed.len()
+        );
+    }
+
+    #[test]
+    fn test_remove_absent() {
+        let mut dbf = DeletableBloomFilter::<&str>::new(32);
+
+        dbf.insert(&"foo");
+        assert!(!dbf.remove(&"bar"));
+        assert!(dbf.contains(&"foo"));
+        assert!(dbf.remove(&"foo"));
+        assert!(!dbf.contains(&"foo"));
+    }
+
+    #[test]
+    fn test_collided() {
+        // With a single region, any collision makes all items undeletable.
+        let mut dbf = DeletableBloomFilter::<u32>::with_region_size(100, 0.01, 1 << 20);
+
+        for i in 0..100 {
+            dbf.insert(&i);
+        }
+        assert_eq!(dbf.regions(), 1);
+        assert_eq!(dbf.stats().deletable_rate, 0.);
+        assert!(!dbf.remove(&0));
+        assert!(dbf.contains(&0));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut dbf = DeletableBloomFilter::<u32>::new(10_000);
+
+        for i in 0..10_000 {
+            dbf.insert(&i);
+        }
+        let stats = dbf.stats();
+        let deletable = (0..10_000).filter(|i| dbf.clone().remove(i)).count();
+        let actual = deletable as f64 / 10_000.;
+
+        assert_eq!(stats.regions, dbf.bits().div_ceil(DEFAULT_REGION_SIZE));
+        assert!((stats.fill_ratio - 0.5).abs() < 0.05);
+        assert!(
+            (stats.deletable_rate - actual).abs() < 0.05,
+            "estimated {} deletable, got {}",
+            stats.deletable_rate,
+            actual
+        );
+        assert!(actual > 0.8);
+    }
+
+    #[test]
+    fn test_to_bloom_filter() {
+        let mut dbf = DeletableBloomFilter::<u32>::new(256);
+        let mut bf = BloomFilter::<u32>::new(256);
+
+        for i in 0..128 {
+            dbf.insert(&i);
+            bf.insert(&i);
+        }
+        assert_eq!(BloomFilter::from(dbf), bf);
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! Binary encoding of filters.
+//!
+//! Filters that carry more state than a bit vector are encoded as a sequence of fixed-width,
+//! little-endian fields followed by their raw storage, and decoded with [`TryFrom<&[u8]>`].
+use std::fmt;
+
+/// An error decoding a filter from bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended before the filter was fully decoded.
+    UnexpectedEof,
+    /// The input has bytes left over after the filter was decoded.
+    TrailingBytes(usize),
+    /// A field has a value that isn't valid for this filter.
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof => write!(f, "unexpected end of input"),
+            Self::TrailingBytes(n) => write!(f, "{} trailing byte(s) after filter", n),
+            Self::InvalidField(field) => write!(f, "invalid value for field `{}`", field),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Reads fixed-width little-endian fields from a byte slice.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64, DecodeError> {
+        self.u64().map(f64::from_bits)
+    }
+
+    /// Read a `u64` field that must fit in a `usize`.
+    pub(crate) fn usize(&mut self, field: &'static str) -> Result<usize, DecodeError> {
+        usize::try_from(self.u64()?).map_err(|_| DecodeError::InvalidField(field))
+    }
+
+    /// Read all the remaining bytes.
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.bytes)
+    }
+
+    /// Check that the whole input was consumed.
+    pub(crate) fn finish(self) -> Result<(), DecodeError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes(self.bytes.len()))
+        }
+    }
+}
@@ -0,0 +1,625 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! Static xor and binary fuse filters, for sets that are built once and never modified.
+//!
+//! As described by Graf and Lemire in *Xor Filters: Faster and Smaller Than Bloom and Cuckoo
+//! Filters* and *Binary Fuse Filters: Fast and Smaller Than Xor Filters*, each key maps to
+//! three slots of an array of fingerprints, and the array is solved such that the xor of a
+//! key's three slots equals the key's fingerprint. A lookup is three memory accesses and
+//! a comparison.
+//!
+//! The array is solved by *peeling*: a slot that only a single key maps to can be assigned
+//! last, since no other key depends on it. Keys are peeled off one by one, and slots are
+//! then assigned in reverse order. Peeling fails with a small probability, in which case
+//! the filter is rebuilt with a new seed.
+//!
+//! [`Xor8`] uses about `9.84` bits per key, while binary fuse filters, which map keys to
+//! three nearby segments of the array, use as little as `9` bits per key for large sets
+//! with [`BinaryFuse8`], and `18` with [`BinaryFuse16`], for false positive rates of `1/256`
+//! and `1/65536`.
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::BitXor;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom;
+use crate::encoding::{DecodeError, Reader};
+use crate::rng::Rng;
+
+/// Maximum number of seeds tried before construction fails.
+const MAX_ATTEMPTS: usize = 100;
+
+/// Seed of the random number generator used to pick hash seeds.
+const RNG_SEED: u64 = 0x726a_0c9b_4d3f_e851;
+
+/// An error building a static filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// Peeling failed with every seed that was tried.
+    Unsolvable,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsolvable => write!(f, "unable to build filter after {} attempts", MAX_ATTEMPTS),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A fingerprint stored in a static filter.
+pub trait Fingerprint: Copy + Default + Eq + BitXor<Output = Self> + fmt::Debug {
+    /// Size of a fingerprint, in bytes.
+    const BYTES: usize;
+
+    /// Derive a fingerprint from a hash.
+    fn from_hash(hash: u64) -> Self;
+    /// Write the fingerprint in little-endian order.
+    fn write(self, bytes: &mut Vec<u8>);
+    /// Read a fingerprint from little-endian bytes.
+    fn read(bytes: &[u8]) -> Self;
+}
+
+impl Fingerprint for u8 {
+    const BYTES: usize = 1;
+
+    fn from_hash(hash: u64) -> Self {
+        (hash ^ (hash >> 32)) as u8
+    }
+
+    fn write(self, bytes: &mut Vec<u8>) {
+        bytes.push(self);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        bytes[0]
+    }
+}
+
+impl Fingerprint for u16 {
+    const BYTES: usize = 2;
+
+    fn from_hash(hash: u64) -> Self {
+        (hash ^ (hash >> 32)) as u16
+    }
+
+    fn write(self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+}
+
+/// A xor filter with 8-bit fingerprints, holding items of type `K`.
+#[derive(Debug)]
+pub struct Xor8<K> {
+    fingerprints: Vec<u8>,
+    block_length: usize,
+    seed: u64,
+    len: usize,
+    hasher: SipHasher13,
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> Xor8<K> {
+    /// Build a filter from a set of keys. Duplicate keys are ignored.
+    pub fn from_keys<I>(keys: I) -> Result<Self, BuildError>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<K>,
+    {
+        let hasher = bloom::hashers()[0];
+        let hashes = unique_hashes(&hasher, keys);
+        let block_length = (1.23 * hashes.len() as f64).ceil() as usize / 3 + 11;
+        let mut rng = Rng::new(RNG_SEED);
+
+        for _ in 0..MAX_ATTEMPTS {
+            let seed = rng.u64();
+            let positions = |h: u64| xor_positions(mix(h, seed), block_length);
+
+            if let Some(fingerprints) = solve(&hashes, seed, block_length * 3, positions) {
+                return Ok(Self {
+                    fingerprints,
+                    block_length,
+                    seed,
+                    len: hashes.len(),
+                    hasher,
+                    key: PhantomData,
+                });
+            }
+        }
+        Err(BuildError::Unsolvable)
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        let hash = mix(bloom::sip_hash(&self.hasher, item), self.seed);
+        let [a, b, c] = xor_positions(hash, self.block_length);
+
+        u8::from_hash(hash) == self.fingerprints[a] ^ self.fingerprints[b] ^ self.fingerprints[c]
+    }
+}
+
+impl<K> Xor8<K> {
+    /// Return the number of keys the filter was built from.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the filter was built from an empty set.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.fingerprints.len() * 8
+    }
+}
+
+impl<K> Clone for Xor8<K> {
+    fn clone(&self) -> Self {
+        Self {
+            fingerprints: self.fingerprints.clone(),
+            block_length: self.block_length,
+            seed: self.seed,
+            len: self.len,
+            hasher: self.hasher,
+            key: self.key,
+        }
+    }
+}
+
+impl<K> From<Xor8<K>> for Vec<u8> {
+    /// Encode the filter as its seed, number of keys and block length, followed by the
+    /// fingerprints.
+    fn from(other: Xor8<K>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&other.seed.to_le_bytes());
+        bytes.extend_from_slice(&(other.len as u64).to_le_bytes());
+        bytes.extend_from_slice(&(other.block_length as u64).to_le_bytes());
+        bytes.extend_from_slice(&other.fingerprints);
+        bytes
+    }
+}
+
+impl<K> TryFrom<&[u8]> for Xor8<K> {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let seed = reader.u64()?;
+        let len = reader.usize("len")?;
+        let block_length = reader.usize("block_length")?;
+
+        if block_length == 0 {
+            return Err(DecodeError::InvalidField("block_length"));
+        }
+        let size = block_length
+            .checked_mul(3)
+            .ok_or(DecodeError::InvalidField("block_length"))?;
+        let fingerprints = reader.bytes(size)?.to_vec();
+
+        reader.finish()?;
+
+        Ok(Self {
+            fingerprints,
+            block_length,
+            seed,
+            len,
+            hasher: bloom::hashers()[0],
+            key: PhantomData,
+        })
+    }
+}
+
+/// A binary fuse filter with fingerprints of type `F`, holding items of type `K`.
+#[derive(Debug)]
+pub struct BinaryFuse<F, K> {
+    fingerprints: Vec<F>,
+    segment_length: usize,
+    segment_count: usize,
+    seed: u64,
+    len: usize,
+    hasher: SipHasher13,
+    key: PhantomData<K>,
+}
+
+/// A binary fuse filter with 8-bit fingerprints, with a false positive rate of `1/256`.
+pub type BinaryFuse8<K> = BinaryFuse<u8, K>;
+
+/// A binary fuse filter with 16-bit fingerprints, with a false positive rate of `1/65536`.
+pub type BinaryFuse16<K> = BinaryFuse<u16, K>;
+
+impl<F: Fingerprint, K: Hash> BinaryFuse<F, K> {
+    /// Build a filter from a set of keys. Duplicate keys are ignored.
+    pub fn from_keys<I>(keys: I) -> Result<Self, BuildError>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<K>,
+    {
+        let hasher = bloom::hashers()[0];
+        let hashes = unique_hashes(&hasher, keys);
+        let (segment_length, segment_count) = fuse_dimensions(hashes.len());
+        let size = (segment_count + 2) * segment_length;
+        let mut rng = Rng::new(RNG_SEED);
+
+        for _ in 0..MAX_ATTEMPTS {
+            let seed = rng.u64();
+            let positions = |h: u64| fuse_positions(mix(h, seed), segment_length, segment_count);
+
+            if let Some(fingerprints) = solve(&hashes, seed, size, positions) {
+                return Ok(Self {
+                    fingerprints,
+                    segment_length,
+                    segment_count,
+                    seed,
+                    len: hashes.len(),
+                    hasher,
+                    key: PhantomData,
+                });
+            }
+        }
+        Err(BuildError::Unsolvable)
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        let hash = mix(bloom::sip_hash(&self.hasher, item), self.seed);
+        let [a, b, c] = fuse_positions(hash, self.segment_length, self.segment_count);
+
+        F::from_hash(hash) == self.fingerprints[a] ^ self.fingerprints[b] ^ self.fingerprints[c]
+    }
+}
+
+impl<F, K> BinaryFuse<F, K> {
+    /// Return the number of keys the filter was built from.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the filter was built from an empty set.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.fingerprints.len() * std::mem::size_of::<F>() * 8
+    }
+}
+
+impl<F: Clone, K> Clone for BinaryFuse<F, K> {
+    fn clone(&self) -> Self {
+        Self {
+            fingerprints: self.fingerprints.clone(),
+            segment_length: self.segment_length,
+            segment_count: self.segment_count,
+            seed: self.seed,
+            len: self.len,
+            hasher: self.hasher,
+            key: self.key,
+        }
+    }
+}
+
+impl<F: Fingerprint, K> From<BinaryFuse<F, K>> for Vec<u8> {
+    /// Encode the filter as its seed, number of keys, segment length and segment count,
+    /// followed by the fingerprints.
+    fn from(other: BinaryFuse<F, K>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&other.seed.to_le_bytes());
+        bytes.extend_from_slice(&(other.len as u64).to_le_bytes());
+        bytes.extend_from_slice(&(other.segment_length as u32).to_le_bytes());
+        bytes.extend_from_slice(&(other.segment_count as u32).to_le_bytes());
+
+        for f in other.fingerprints {
+            f.write(&mut bytes);
+        }
+        bytes
+    }
+}
+
+impl<F: Fingerprint, K> TryFrom<&[u8]> for BinaryFuse<F, K> {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let seed = reader.u64()?;
+        let len = reader.usize("len")?;
+        let segment_length = reader.u32()? as usize;
+        let segment_count = reader.u32()? as usize;
+
+        if !segment_length.is_power_of_two() {
+            return Err(DecodeError::InvalidField("segment_length"));
+        }
+        if segment_count == 0 {
+            return Err(DecodeError::InvalidField("segment_count"));
+        }
+        let size = segment_count
+            .checked_add(2)
+            .and_then(|n| n.checked_mul(segment_length))
+            .ok_or(DecodeError::InvalidField("segment_count"))?;
+        let fingerprints: Vec<F> = reader
+            .bytes(
+                size.checked_mul(F::BYTES)
+                    .ok_or(DecodeError::InvalidField("segment_count"))?,
+            )?
+            .chunks_exact(F::BYTES)
+            .map(F::read)
+            .collect();
+
+        reader.finish()?;
+
+        if fingerprints.len() != size {
+            return Err(DecodeError::InvalidField("fingerprints"));
+        }
+
+        Ok(Self {
+            fingerprints,
+            segment_length,
+            segment_count,
+            seed,
+            len,
+            hasher: bloom::hashers()[0],
+            key: PhantomData,
+        })
+    }
+}
+
+/// Hash all keys, removing duplicate hashes, since they can never be peeled.
+fn unique_hashes<K, I>(hasher: &SipHasher13, keys: I) -> Vec<u64>
+where
+    K: Hash,
+    I: IntoIterator,
+    I::Item: Borrow<K>,
+{
+    let mut hashes: Vec<u64> = keys
+        .into_iter()
+        .map(|k| bloom::sip_hash(hasher, k.borrow()))
+        .collect();
+
+    hashes.sort_unstable();
+    hashes.dedup();
+    hashes
+}
+
+/// Mix a key hash with a seed.
+fn mix(hash: u64, seed: u64) -> u64 {
+    bloom::mix(hash.wrapping_add(seed))
+}
+
+/// Map a hash to `0..n`, using the high bits of a multiplication.
+fn reduce(hash: u32, n: usize) -> usize {
+    ((hash as u64 * n as u64) >> 32) as usize
+}
+
+/// Return the three slots of a hash in a xor filter, one per block.
+fn xor_positions(hash: u64, block_length: usize) -> [usize; 3] {
+    [
+        reduce(hash as u32, block_length),
+        block_length + reduce(hash.rotate_left(21) as u32, block_length),
+        2 * block_length + reduce(hash.rotate_left(42) as u32, block_length),
+    ]
+}
+
+/// Return the three slots of a hash in a binary fuse filter, in three consecutive segments.
+fn fuse_positions(hash: u64, segment_length: usize, segment_count: usize) -> [usize; 3] {
+    let mask = segment_length as u64 - 1;
+    let h0 = ((hash as u128 * (segment_count * segment_length) as u128) >> 64) as u64;
+    let h1 = h0 + segment_length as u64;
+    let h2 = h1 + segment_length as u64;
+    let hh = hash & ((1 << 36) - 1);
+
+    [
+        (h0 ^ ((hh >> 36) & mask)) as usize,
+        (h1 ^ ((hh >> 18) & mask)) as usize,
+        (h2 ^ (hh & mask)) as usize,
+    ]
+}
+
+/// Return the segment length and segment count of a binary fuse filter for `n` keys.
+fn fuse_dimensions(n: usize) -> (usize, usize) {
+    let segment_length = if n == 0 {
+        4
+    } else {
+        let exp = ((n as f64).ln() / 3.33_f64.ln() + 2.25).floor() as u32;
+        (1usize << exp).min(1 << 18)
+    };
+    let capacity = if n <= 1 {
+        0
+    } else {
+        let factor = f64::max(1.125, 0.875 + 0.25 * 1e6_f64.ln() / (n as f64).ln());
+        (n as f64 * factor).round() as usize
+    };
+    let initial = capacity.div_ceil(segment_length).saturating_sub(2);
+    let size = (initial + 2) * segment_length;
+    let segment_count = size.div_ceil(segment_length);
+    let segment_count = if segment_count <= 2 {
+        1
+    } else {
+        segment_count - 2
+    };
+    (segment_length, segment_count)
+}
+
+/// Solve the fingerprint array of the given size for a set of unique key hashes, by
+/// peeling. Returns `None` if peeling fails.
+fn solve<F, P>(hashes: &[u64], seed: u64, size: usize, positions: P) -> Option<Vec<F>>
+where
+    F: Fingerprint,
+    P: Fn(u64) -> [usize; 3],
+{
+    // For each slot, the number of keys mapping to it, and the xor of their hashes.
+    let mut counts = vec![0u32; size];
+    let mut xors = vec![0u64; size];
+
+    for h in hashes {
+        for p in positions(*h) {
+            counts[p] += 1;
+            xors[p] ^= h;
+        }
+    }
+    let mut queue: Vec<usize> = (0..size).filter(|i| counts[*i] == 1).collect();
+    let mut stack = Vec::with_capacity(hashes.len());
+
+    while let Some(slot) = queue.pop() {
+        if counts[slot] != 1 {
+            continue;
+        }
+        // The only key left in this slot.
+        let h = xors[slot];
+
+        for p in positions(h) {
+            counts[p] -= 1;
+            xors[p] ^= h;
+
+            if counts[p] == 1 {
+                queue.push(p);
+            }
+        }
+        stack.push((h, slot));
+    }
+    if stack.len() != hashes.len() {
+        return None;
+    }
+    let mut fingerprints = vec![F::default(); size];
+
+    for (h, slot) in stack.into_iter().rev() {
+        let f = positions(h)
+            .into_iter()
+            .filter(|p| *p != slot)
+            .fold(F::from_hash(mix(h, seed)), |f, p| f ^ fingerprints[p]);
+
+        fingerprints[slot] = f;
+    }
+    Some(fingerprints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BloomFilter;
+
+    fn fp_rate(contains: impl Fn(&u64) -> bool) -> f64 {
+        let trials = 1_000_000;
+        let false_positives = (1_000_000..1_000_000 + trials).filter(contains).count();
+
+        false_positives as f64 / trials as f64
+    }
+
+    #[test]
+    fn test_xor8() {
+        let keys: Vec<u64> = (0..100_000).collect();
+        let filter = Xor8::<u64>::from_keys(&keys).unwrap();
+
+        for k in &keys {
+            assert!(filter.contains(k), "key {} resulted in a false negative", k);
+        }
+        let rate = fp_rate(|k| filter.contains(k));
+        let bits_per_key = filter.bits() as f64 / keys.len() as f64;
+
+        assert!(rate < 0.005, "false positive rate {} is too high", rate);
+        assert!(bits_per_key < 10., "{} bits per key", bits_per_key);
+    }
+
+    #[test]
+    fn test_binary_fuse8() {
+        let keys: Vec<u64> = (0..100_000).collect();
+        let filter = BinaryFuse8::<u64>::from_keys(&keys).unwrap();
+
+        for k in &keys {
+            assert!(filter.contains(k), "key {} resulted in a false negative", k);
+        }
+        let rate = fp_rate(|k| filter.contains(k));
+        let bits_per_key = filter.bits() as f64 / keys.len() as f64;
+
+        assert!(rate < 0.005, "false positive rate {} is too high", rate);
+        assert!(bits_per_key < 9.6, "{} bits per key", bits_per_key);
+
+        // Smaller than a Bloom filter with the same false positive rate.
+        let bloom = BloomFilter::<u64>::with_rate(keys.len(), 1. / 256.);
+        assert!((filter.bits() as f64) < bloom.bits() as f64 * 0.85);
+    }
+
+    #[test]
+    fn test_binary_fuse16() {
+        let keys: Vec<u64> = (0..100_000).collect();
+        let filter = BinaryFuse16::<u64>::from_keys(&keys).unwrap();
+
+        for k in &keys {
+            assert!(filter.contains(k), "key {} resulted in a false negative", k);
+        }
+        let rate = fp_rate(|k| filter.contains(k));
+
+        assert!(rate < 0.0001, "false positive rate {} is too high", rate);
+    }
+
+    #[test]
+    fn test_small_sets() {
+        for n in 0..64u64 {
+            let xor = Xor8::<u64>::from_keys(0..n).unwrap();
+            let fuse = BinaryFuse8::<u64>::from_keys(0..n).unwrap();
+
+            assert_eq!(fuse.len(), n as usize);
+            for k in 0..n {
+                assert!(xor.contains(&k));
+                assert!(fuse.contains(&k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let keys = ["foo", "bar", "foo", "baz", "bar"];
+        let filter = BinaryFuse16::<&str>::from_keys(keys).unwrap();
+
+        assert_eq!(filter.len(), 3);
+        assert!(filter.contains(&"foo"));
+        assert!(filter.contains(&"bar"));
+        assert!(filter.contains(&"baz"));
+    }
+
+    #[test]
+    fn test_encoding() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key-{}", i)).collect();
+
+        let a = BinaryFuse16::<String>::from_keys(&keys).unwrap();
+        let bytes: Vec<u8> = a.clone().into();
+        let b = BinaryFuse16::<String>::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(a.fingerprints, b.fingerprints);
+        assert!(keys.iter().all(|k| b.contains(k)));
+        assert!(BinaryFuse16::<String>::try_from(&bytes[..bytes.len() - 1]).is_err());
+
+        // A size that overflows once multiplied by the fingerprint width.
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&(1u32 << 31).to_le_bytes());
+        bytes.extend_from_slice(&(u32::MAX - 1).to_le_bytes());
+
+        assert_eq!(
+            BinaryFuse16::<String>::try_from(bytes.as_slice()).unwrap_err(),
+            DecodeError::InvalidField("segment_count")
+        );
+
+        let a = Xor8::<String>::from_keys(&keys).unwrap();
+        let bytes: Vec<u8> = a.clone().into();
+        let b = Xor8::<String>::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(a.fingerprints, b.fingerprints);
+        assert!(keys.iter().all(|k| b.contains(k)));
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! An invertible Bloom lookup table, for set reconciliation.
+//!
+//! As described by Eppstein, Goodrich, Uyeda and Varghese in *What's the Difference?
+//! Efficient Set Reconciliation without Prior Context*, each cell of the table holds a
+//! count, the XOR of the keys mapped to it, and the XOR of their hashes. Each key is added
+//! to one cell in each of `k` partitions. Unlike a Bloom filter, the table can be
+//! *subtracted* from another: keys present on both sides cancel out, leaving only the
+//! symmetric difference of the two sets, which can be listed as long as it is small
+//! compared to the number of cells.
+//!
+//! Listing works by *peeling*: a *pure* cell, holding a single key, is recognized by a
+//! count of `±1` and a hash sum matching its key sum. Its key is removed from all its
+//! cells, which may make other cells pure, and so on until the table is empty, or no pure
+//! cells are left.
+//!
+//! To size a table when the difference is unknown, both sides first exchange a
+//! [`StrataEstimator`], which estimates the size of the difference in a fixed space.
+use std::collections::VecDeque;
+use std::hash::Hasher;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom;
+
+/// The default number of cells per key.
+pub const DEFAULT_HASHES: usize = 4;
+
+/// Number of cells per item of difference used when sizing a table, for the default
+/// number of hashes. Peeling large tables succeeds with high probability above `1.3`, but
+/// small tables need more room.
+const CELLS_PER_ITEM: f64 = 2.;
+
+/// Number of cells added to every table sized for a difference, for small differences.
+const SPARE_CELLS: usize = 32;
+
+/// Number of strata in a strata estimator.
+const STRATA: usize = 32;
+
+/// Number of cells of each stratum's table.
+const STRATUM_CELLS: usize = 80;
+
+/// Seed of the hasher used to assign keys to strata.
+const STRATA_SEED: (u64, u64) = (0x6a1f_0c3d_b2e8_9475, 0x93d4_57e1_0a6c_2fb8);
+
+/// A single cell of the table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Cell {
+    count: i64,
+    key_sum: u64,
+    hash_sum: u64,
+}
+
+impl Cell {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The entries of a table, as listed by [`InvertibleBloomFilter::list_entries`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entries {
+    /// Keys that were inserted. After a subtraction `a - b`, keys only in `a`.
+    pub positive: Vec<u64>,
+    /// Keys that were removed. After a subtraction `a - b`, keys only in `b`.
+    pub negative: Vec<u64>,
+}
+
+impl Entries {
+    /// Return the total number of entries.
+    pub fn len(&self) -> usize {
+        self.positive.len() + self.negative.len()
+    }
+
+    /// Check whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.positive.is_empty() && self.negative.is_empty()
+    }
+}
+
+/// An invertible Bloom lookup table of `u64` keys, eg. object IDs.
+#[derive(Clone, Debug)]
+pub struct InvertibleBloomFilter {
+    cells: Vec<Cell>,
+    nhashes: usize,
+    hashers: [SipHasher13; 2],
+}
+
+impl InvertibleBloomFilter {
+    /// Return a new table able to list a difference of up to approximately `capacity`
+    /// keys. The default number of hashes is used.
+    pub fn new(capacity: usize) -> Self {
+        let ncells = (capacity as f64 * CELLS_PER_ITEM).ceil() as usize + SPARE_CELLS;
+        Self::with_params(ncells, DEFAULT_HASHES)
+    }
+
+    /// Return a new table with the given number of cells and hashes. The number of cells
+    /// is rounded up to a multiple of the number of hashes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of cells or hashes is zero.
+    pub fn with_params(ncells: usize, nhashes: usize) -> Self {
+        assert!(nhashes > 0, "number of hashes must be greater than zero");
+        assert!(ncells > 0, "number of cells must be greater than zero");
+
+        Self {
+            cells: vec![Cell::default(); ncells.div_ceil(nhashes) * nhashes],
+            nhashes,
+            hashers: bloom::hashers(),
+        }
+    }
+
+    /// Add a key to the table.
+    pub fn insert(&mut self, key: u64) {
+        self.update(key, 1);
+    }
+
+    /// Remove a key from the table. Removing a key that wasn't inserted records it as a
+    /// negative entry.
+    pub fn remove(&mut self, key: u64) {
+        self.update(key, -1);
+    }
+
+    /// Return the number of cells.
+    pub fn cells(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Return the number of cells each key is added to.
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Check whether the table holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.cells.iter().all(Cell::is_empty)
+    }
+
+    /// Remove all entries.
+    pub fn clear(&mut self) {
+        self.cells.iter_mut().for_each(|c| *c = Cell::default());
+    }
+
+    /// Check whether two tables have the same number of cells and hash functions, and
+    /// can be subtracted.
+    pub fn is_comparable(&self, other: &Self) -> bool {
+        self.nhashes == other.nhashes
+            && self.cells.len() == other.cells.len()
+            && self.hashers[0].keys() == other.hashers[0].keys()
+            && self.hashers[1].keys() == other.hashers[1].keys()
+    }
+
+    /// Return the difference of two comparable tables. Keys inserted in both tables
+    /// cancel out: listing the result yields keys only in `self` as positive entries, and
+    /// keys only in `other` as negative entries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the tables are not comparable.
+    pub fn subtract(&self, other: &Self) -> Self {
+        assert!(
+            self.is_comparable(other),
+            "unable to subtract tables with different configurations"
+        );
+        let cells = self
+            .cells
+            .iter()
+            .zip(&other.cells)
+            .map(|(a, b)| Cell {
+                count: a.count.wrapping_sub(b.count),
+                key_sum: a.key_sum ^ b.key_sum,
+                hash_sum: a.hash_sum ^ b.hash_sum,
+            })
+            .collect();
+
+        Self {
+            cells,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+        }
+    }
+
+    /// List the entries of the table, by peeling. Returns `None` if the table holds too
+    /// many entries to be listed completely.
+    pub fn list_entries(&self) -> Option<Entries> {
+        let mut table = self.clone();
+        let mut entries = Entries::default();
+        let mut pure: VecDeque<usize> = (0..table.cells.len())
+            .filter(|i| table.is_pure(*i))
+            .collect();
+
+        while let Some(i) = pure.pop_front() {
+            // The cell may have been peeled since it was queued.
+            if !table.is_pure(i) {
+                continue;
+            }
+            let Cell { count, key_sum, .. } = table.cells[i];
+
+            if count == 1 {
+                entries.positive.push(key_sum);
+            } else {
+                entries.negative.push(key_sum);
+            }
+            let (h1, h2) = table.update(key_sum, -count);
+
+            for j in table.indices(h1, h2) {
+                if table.is_pure(j) {
+                    pure.push_back(j);
+                }
+            }
+        }
+
+        if table.is_empty() {
+            Some(entries)
+        } else {
+            None
+        }
+    }
+
+    /// Add `count` to the cells of a key, returning the hash of the key.
+    fn update(&mut self, key: u64, count: i64) -> (u64, u64) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, &key);
+
+        for i in self.indices(h1, h2) {
+            let cell = &mut self.cells[i];
+
+            cell.count = cell.count.wrapping_add(count);
+            cell.key_sum ^= key;
+            cell.hash_sum ^= h2;
+        }
+        (h1, h2)
+    }
+
+    /// Return the indices of the cells of a key, one per partition.
+    ///
+    /// Unlike in a Bloom filter, the indices can't simply be derived by double hashing:
+    /// two keys with the same `H1(x)` and `H2(x)` modulo the partition size would share
+    /// all their cells, and never be peeled. With a table sized for the difference, this
+    /// happens to some pair of keys with constant probability, so each index is mixed.
+    fn indices(&self, h1: u64, h2: u64) -> impl Iterator<Item = usize> {
+        let slice = self.cells.len() / self.nhashes;
+
+        (0..self.nhashes).map(move |i| {
+            let h = bloom::mix(h1.wrapping_add((i as u64).wrapping_mul(h2)));
+            i * slice + ((h as u128 * slice as u128) >> 64) as usize
+        })
+    }
+
+    /// Check whether a cell holds a single key.
+    fn is_pure(&self, i: usize) -> bool {
+        let cell = &self.cells[i];
+
+        if cell.count != 1 && cell.count != -1 {
+            return false;
+        }
+        let (_, h2) = bloom::sip_hashes(&self.hashers, &cell.key_sum);
+        cell.hash_sum == h2
+    }
+}
+
+/// Estimates the size of the symmetric difference of two sets.
+///
+/// Keys are split into strata by the number of trailing zeros of their hash, so that
+/// stratum `i` holds about `1 / 2^(i+1)` of the keys, and each stratum is added to a small
+/// [`InvertibleBloomFilter`]. After subtracting the estimators of two sets, strata are
+/// listed from the sparsest down; when a stratum can't be listed, the number of keys found
+/// so far is scaled up by the fraction of keys in the strata above it.
+#[derive(Clone, Debug)]
+pub struct StrataEstimator {
+    strata: Vec<InvertibleBloomFilter>,
+    hasher: SipHasher13,
+}
+
+impl Default for StrataEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StrataEstimator {
+    /// Return a new, empty estimator.
+    pub fn new() -> Self {
+        Self {
+            strata: vec![InvertibleBloomFilter::with_params(STRATUM_CELLS, DEFAULT_HASHES); STRATA],
+            hasher: SipHasher13::new_with_keys(STRATA_SEED.0, STRATA_SEED.1),
+        }
+    }
+
+    /// Add a key to the estimator.
+    pub fn insert(&mut self, key: u64) {
+        let stratum = self.stratum(key);
+        self.strata[stratum].insert(key);
+    }
+
+    /// Remove a key from the estimator.
+    pub fn remove(&mut self, key: u64) {
+        let stratum = self.stratum(key);
+        self.strata[stratum].remove(key);
+    }
+
+    /// Return the difference of two estimators, stratum by stratum.
+    pub fn subtract(&self, other: &Self) -> Self {
+        Self {
+            strata: self
+                .strata
+                .iter()
+                .zip(&other.strata)
+                .map(|(a, b)| a.subtract(b))
+                .collect(),
+            hasher: self.hasher,
+        }
+    }
+
+    /// Estimate the number of keys in the estimator. After a subtraction, this is the size
+    /// of the symmetric difference of the two sets.
+    pub fn estimate(&self) -> usize {
+        let mut count = 0;
+
+        for (i, stratum) in self.strata.iter().enumerate().rev() {
+            match stratum.list_entries() {
+                Some(entries) => count += entries.len(),
+                None => return count << (i + 1),
+            }
+        }
+        count
+    }
+
+    /// Return a table sized for the estimated difference, with some slack for estimation
+    /// error.
+    pub fn table(&self) -> InvertibleBloomFilter {
+        InvertibleBloomFilter::new(self.estimate() * 2)
+    }
+
+    fn stratum(&self, key: u64) -> usize {
+        let mut hasher = self.hasher;
+        hasher.write_u64(key);
+
+        (hasher.finish().trailing_zeros() as usize).min(STRATA - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut iblt = InvertibleBloomFilter::new(10);
+
+        for key in 0..10 {
+            iblt.insert(key);
+        }
+        let entries = iblt.list_entries().unwrap();
+        let mut positive = entries.positive;
+
+        positive.sort_unstable();
+        assert_eq!(positive, (0..10).collect::<Vec<_>>());
+        assert!(entries.negative.is_empty());
+
+        for key in 0..10 {
+            iblt.remove(key);
+        }
+        assert!(iblt.is_empty());
+
+        iblt.remove(42);
+        assert_eq!(iblt.list_entries().unwrap().negative, vec![42]);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let mut rng = Rng::new(1);
+        let shared = (0..10_000).map(|_| rng.u64()).collect::<Vec<_>>();
+        let ours = (0..30).map(|_| rng.u64()).collect::<Vec<_>>();
+        let theirs = (0..20).map(|_| rng.u64()).collect::<Vec<_>>();
+
+        let mut a = InvertibleBloomFilter::new(100);
+        let mut b = InvertibleBloomFilter::new(100);
+
+        for key in shared.iter().chain(&ours) {
+            a.insert(*key);
+        }
+        for key in shared.iter().chain(&theirs) {
+            b.insert(*key);
+        }
+        let mut entries = a.subtract(&b).list_entries().unwrap();
+        let (mut ours, mut theirs) = (ours, theirs);
+
+        for keys in [
+            &mut entries.positive,
+            &mut entries.negative,
+            &mut ours,
+            &mut theirs,
+        ] {
+            keys.sort_unstable();
+        }
+        assert_eq!(entries.positive, ours);
+        assert_eq!(entries.negative, theirs);
+    }
+
+    #[test]
+    fn test_overloaded() {
+        let mut iblt = InvertibleBloomFilter::new(10);
+
+        for key in 0..1000 {
+            iblt.insert(key);
+        }
+        assert_eq!(iblt.list_entries(), None);
+    }
+
+    #[test]
+    fn test_strata_estimator() {
+        let mut rng = Rng::new(2);
+
+        for difference in [0, 10, 100, 1000, 10_000] {
+            let mut a = StrataEstimator::new();
+            let mut b = StrataEstimator::new();
+
+            for _ in 0..10_000 {
+                let key = rng.u64();
+                a.insert(key);
+                b.insert(key);
+            }
+            for _ in 0..difference {
+                a.insert(rng.u64());
+            }
+            let estimate = a.subtract(&b).estimate();
+
+            assert!(
+                estimate as f64 >= difference as f64 * 0.5 && estimate <= difference * 2,
+                "estimated {} for a difference of {}",
+                estimate,
+                difference
+            );
+        }
+    }
+
+    #[test]
+    fn test_strata_sizing() {
+        let mut rng = Rng::new(3);
+        let mut a = StrataEstimator::new();
+        let b = StrataEstimator::new();
+        let keys = (0..500).map(|_| rng.u64()).collect::<Vec<_>>();
+
+        for key in &keys {
+            a.insert(*key);
+        }
+        let mut table = a.subtract(&b).table();
+
+        for key in &keys {
+            table.insert(*key);
+        }
+        assert_eq!(table.list_entries().unwrap().len(), 500);
+    }
+}
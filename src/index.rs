@@ -0,0 +1,316 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A bit-sliced index over many Bloom filters, for asking which filters contain an item.
+//!
+//! As in BitFunnel, described by Goodwin et al. in *BitFunnel: Revisiting Signatures for
+//! Search*, the filters are stored *transposed*: instead of one row of `m` bits per filter,
+//! the index has one row of `N` bits per bit position, where bit `j` of row `i` is bit `i`
+//! of filter `j`. Looking up an item reads only the `k` rows of its bit positions and ANDs
+//! them together, yielding a bitmap of the filters that contain it, instead of probing
+//! each of the `N` filters in turn.
+//!
+//! All filters of an index must be comparable, ie. have the same size, number of hashes
+//! and hashers, and are identified by the id returned when they are added.
+use std::hash::Hash;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, BloomFilter};
+
+/// An index over comparable Bloom filters of items of type `K`.
+#[derive(Debug)]
+pub struct BloomIndex<K> {
+    /// One row per bit position, each of `words` words.
+    rows: Vec<u64>,
+    /// Number of words per row.
+    words: usize,
+    /// Bitmap of the ids that are in use, of `words` words.
+    members: Vec<u64>,
+    /// One past the largest id assigned so far.
+    ids: usize,
+    /// An empty filter with the configuration of all members.
+    template: BloomFilter<K>,
+}
+
+impl<K> BloomIndex<K> {
+    /// Return a new, empty index, for filters that are comparable with the given filter.
+    pub fn new(template: &BloomFilter<K>) -> Self {
+        let mut template = template.clone();
+        template.clear();
+
+        Self {
+            rows: Vec::new(),
+            words: 0,
+            members: Vec::new(),
+            ids: 0,
+            template,
+        }
+    }
+
+    /// Return a new index holding the given filters, which are assigned consecutive ids
+    /// starting from zero. Returns `None` if there are no filters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the filters are not all comparable.
+    pub fn from_filters<'a>(filters: impl IntoIterator<Item = &'a BloomFilter<K>>) -> Option<Self>
+    where
+        K: 'a,
+    {
+        let mut filters = filters.into_iter().peekable();
+        let mut index = Self::new(filters.peek()?);
+
+        for filter in filters {
+            index.add(filter);
+        }
+        Some(index)
+    }
+
+    /// Add a filter to the index, returning its id. Ids of removed filters are reused.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the filter is not comparable with the filters of the index.
+    pub fn add(&mut self, filter: &BloomFilter<K>) -> usize {
+        assert!(
+            self.template.is_comparable(filter),
+            "unable to index filters with different configurations"
+        );
+        // Bits past the last id are clear, so the first clear bit is the lowest free id.
+        let id = self
+            .members
+            .iter()
+            .position(|m| *m != u64::MAX)
+            .map_or(self.members.len() * 64, |word| {
+                word * 64 + self.members[word].trailing_ones() as usize
+            });
+
+        if id >= self.words * 64 {
+            self.grow();
+        }
+        self.ids = self.ids.max(id + 1);
+
+        let bits = filter.bit_vec();
+        let (word, mask) = (id / 64, 1 << (id % 64));
+
+        self.members[word] |= mask;
+
+        for i in (0..bits.len()).filter(|i| bits.is_set(*i)) {
+            self.rows[i * self.words + word] |= mask;
+        }
+        id
+    }
+
+    /// Remove a filter from the index, returning it. Returns `None` if there is no filter
+    /// with the given id.
+    pub fn remove(&mut self, id: usize) -> Option<BloomFilter<K>> {
+        let filter = self.get(id)?;
+        let (word, mask) = (id / 64, 1 << (id % 64));
+
+        for row in self.rows.chunks_exact_mut(self.words) {
+            row[word] &= !mask;
+        }
+        self.members[word] &= !mask;
+
+        Some(filter)
+    }
+
+    /// Return the filter with the given id, if any.
+    pub fn get(&self, id: usize) -> Option<BloomFilter<K>> {
+        if !self.is_member(id) {
+            return None;
+        }
+        let (word, mask) = (id / 64, 1 << (id % 64));
+        let mut bits = BitVec::new(self.template.bits());
+
+        for (i, row) in self.rows.chunks_exact(self.words).enumerate() {
+            if row[word] & mask != 0 {
+                bits.set(i);
+            }
+        }
+        Some(BloomFilter::from_parts(bits, self.template.hashes()))
+    }
+
+    /// Return the number of filters in the index.
+    pub fn len(&self) -> usize {
+        self.members.iter().map(|m| m.count_ones() as usize).sum()
+    }
+
+    /// Check whether the index holds no filters.
+    pub fn is_empty(&self) -> bool {
+        self.members.iter().all(|m| *m == 0)
+    }
+
+    /// Return one past the largest id assigned so far, ie. the length of the bitmaps
+    /// returned by [`BloomIndex::query`].
+    pub fn ids(&self) -> usize {
+        self.ids
+    }
+
+    /// Check whether a filter can be added to the index.
+    pub fn is_comparable(&self, filter: &BloomFilter<K>) -> bool {
+        self.template.is_comparable(filter)
+    }
+
+    /// Check whether an id is in use.
+    fn is_member(&self, id: usize) -> bool {
+        self.members
+            .get(id / 64)
+            .is_some_and(|m| m & (1 << (id % 64)) != 0)
+    }
+
+    /// Double the number of words per row, to make room for more ids.
+    fn grow(&mut self) {
+        let words = (self.words * 2).max(1);
+        let mut rows = vec![0; self.template.bits() * words];
+
+        for (old, new) in self
+            .rows
+            .chunks_exact(self.words.max(1))
+            .zip(rows.chunks_exact_mut(words))
+        {
+            new[..old.len()].copy_from_slice(old);
+        }
+        self.rows = rows;
+        self.words = words;
+        self.members.resize(words, 0);
+    }
+}
+
+impl<K: Hash> BloomIndex<K> {
+    /// Return the bitmap of ids of the filters that likely contain the given item. Bit `j`
+    /// is set if filter `j` contains the item. Ids that are not in use are never set.
+    pub fn query(&self, item: &K) -> BitVec {
+        let (h1, h2) = bloom::sip_hashes(self.template.hashers(), item);
+        let nbits = self.template.bits() as u64;
+        // Filters without hashes contain every item, but removed ids contain nothing.
+        let mut result = self.members.clone();
+
+        for i in 0..self.template.hashes() {
+            let index = bloom::bloom_hash(h1, h2, i as u64, nbits) as usize;
+            let row = &self.rows[index * self.words..(index + 1) * self.words];
+
+            for (r, w) in result.iter_mut().zip(row) {
+                *r &= w;
+            }
+        }
+        // Ids past the last one are not members, so the padding bits are zero.
+        let bytes = result
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .take(self.ids().div_ceil(8))
+            .collect();
+
+        BitVec::from_bytes(bytes, self.ids()).unwrap()
+    }
+}
+
+impl<K> Clone for BloomIndex<K> {
+    fn clone(&self) -> Self {
+        Self {
+            rows: self.rows.clone(),
+            words: self.words,
+            members: self.members.clone(),
+            ids: self.ids,
+            template: self.template.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(items: std::ops::Range<u32>) -> BloomFilter<u32> {
+        let mut bf = BloomFilter::new(1000);
+        for i in items {
+            bf.insert(&i);
+        }
+        bf
+    }
+
+    #[test]
+    fn test_query() {
+        let filters = (0..200)
+            .map(|j| filter(j * 10..j * 10 + 100))
+            .collect::<Vec<_>>();
+        let index = BloomIndex::from_filters(&filters).unwrap();
+
+        assert_eq!(index.len(), 200);
+        assert_eq!(index.ids(), 200);
+
+        for item in (0..2200).step_by(7) {
+            let bitmap = index.query(&item);
+
+            assert_eq!(bitmap.len(), 200);
+            for (j, filter) in filters.iter().enumerate() {
+                assert_eq!(bitmap.is_set(j), filter.contains(&item));
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut index = BloomIndex::new(&BloomFilter::<u32>::new(1000));
+        let a = index.add(&filter(0..100));
+        let b = index.add(&filter(100..200));
+        let c = index.add(&filter(200..300));
+
+        assert_eq!((a, b, c), (0, 1, 2));
+        assert!(index.query(&150).is_set(b));
+
+        let removed = index.remove(b).unwrap();
+        assert_eq!(removed, filter(100..200));
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.query(&150).count_ones(), 0);
+        assert!(index.remove(b).is_none());
+
+        // The id is reused.
+        let d = index.add(&filter(300..400));
+        assert_eq!(d, b);
+        assert!(index.query(&350).is_set(d));
+        assert_eq!(index.get(c).unwrap(), filter(200..300));
+    }
+
+    #[test]
+    fn test_reuse_ids() {
+        let mut index = BloomIndex::new(&BloomFilter::<u32>::new(1000));
+        for j in 0..130 {
+            assert_eq!(index.add(&filter(j..j + 1)), j as usize);
+        }
+        index.remove(100);
+        index.remove(70);
+
+        assert_eq!(index.add(&filter(0..1)), 70);
+        assert_eq!(index.add(&filter(0..1)), 100);
+        assert_eq!(index.add(&filter(0..1)), 130);
+    }
+
+    #[test]
+    fn test_no_hashes() {
+        // Fewer bits than items, for which the optimal number of hashes is zero.
+        let template = BloomFilter::<u32>::with_rate(100, 0.9);
+        let mut index = BloomIndex::new(&template);
+
+        assert_eq!(template.hashes(), 0);
+
+        let a = index.add(&template);
+        let b = index.add(&template);
+        index.remove(a);
+
+        let bitmap = index.query(&1);
+
+        assert_eq!(bitmap.len(), 2);
+        assert!(!bitmap.is_set(a));
+        assert!(bitmap.is_set(b));
+        assert_eq!(bitmap.is_set(b), index.get(b).unwrap().contains(&1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_incomparable() {
+        let mut index = BloomIndex::new(&BloomFilter::<u32>::new(1000));
+        index.add(&BloomFilter::new(2000));
+    }
+}
@@ -50,7 +50,55 @@
 #![warn(missing_docs)]
 #![allow(clippy::bool_assert_comparison)]
 
+pub mod atomic;
+pub mod attenuated;
 pub mod bitvec;
+pub mod blocked;
 pub mod bloom;
+pub mod cascade;
+pub mod compressed;
+pub mod counting;
+pub mod countmin;
+pub mod cuckoo;
+pub mod deletable;
+pub mod encoding;
+pub mod fuse;
+pub mod iblt;
+pub mod index;
+pub mod partitioned;
+pub mod prefix;
+pub mod quotient;
+pub mod range;
+pub mod ribbon;
+pub mod scalable;
+pub mod sharded;
+pub mod sliding;
+pub mod snapshot;
+pub mod stable;
 
+mod packed;
+mod rng;
+
+pub use atomic::AtomicBloomFilter;
+pub use attenuated::AttenuatedBloomFilter;
+pub use blocked::BlockedBloomFilter;
 pub use bloom::BloomFilter;
+pub use cascade::FilterCascade;
+pub use counting::CountingBloomFilter;
+pub use countmin::CountMinSketch;
+pub use cuckoo::CuckooFilter;
+pub use deletable::DeletableBloomFilter;
+pub use encoding::DecodeError;
+pub use fuse::{BinaryFuse16, BinaryFuse8, Xor8};
+pub use iblt::{InvertibleBloomFilter, StrataEstimator};
+pub use index::BloomIndex;
+pub use partitioned::PartitionedBloomFilter;
+pub use prefix::PrefixBloomFilter;
+pub use quotient::QuotientFilter;
+pub use range::RangeFilter;
+pub use ribbon::RibbonFilter;
+pub use scalable::ScalableBloomFilter;
+pub use sharded::ShardedBloomFilter;
+pub use sliding::SlidingBloomFilter;
+pub use snapshot::SnapshotBloomFilter;
+pub use stable::StableBloomFilter;
@@ -0,0 +1,108 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! Packed arrays of small integers, used for counters and fingerprints.
+
+/// A fixed-size array of packed integers of the same width. Values don't straddle word
+/// boundaries, so widths that don't divide `64` leave some bits unused in each word.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PackedVec {
+    words: Vec<u64>,
+    len: usize,
+    width: u32,
+}
+
+impl PackedVec {
+    /// Create a new array of zeroes of the given width, in bits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the width is not between `1` and `32`.
+    pub(crate) fn new(len: usize, width: u32) -> Self {
+        assert!(
+            (1..=32).contains(&width),
+            "width must be between 1 and 32 bits, got {}",
+            width
+        );
+        Self {
+            words: vec![0; Self::words_for(len, width)],
+            len,
+            width,
+        }
+    }
+
+    /// Create an array from its underlying words. Returns `None` if the number of words
+    /// doesn't match the length and width.
+    pub(crate) fn from_words(words: Vec<u64>, len: usize, width: u32) -> Option<Self> {
+        if !(1..=32).contains(&width) || words.len() != Self::words_for(len, width) {
+            return None;
+        }
+        Some(Self { words, len, width })
+    }
+
+    /// Return the number of words needed to store `len` values of the given width.
+    pub(crate) fn words_for(len: usize, width: u32) -> usize {
+        len.div_ceil((64 / width) as usize)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Return the underlying words.
+    pub(crate) fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Return the maximum value that can be stored.
+    pub(crate) fn max(&self) -> u64 {
+        (1 << self.width) - 1
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.words.iter_mut().for_each(|w| *w = 0);
+    }
+
+    pub(crate) fn get(&self, index: usize) -> u64 {
+        let per_word = (64 / self.width) as usize;
+        let shift = (index % per_word) as u32 * self.width;
+
+        (self.words[index / per_word] >> shift) & self.max()
+    }
+
+    pub(crate) fn put(&mut self, index: usize, value: u64) {
+        let per_word = (64 / self.width) as usize;
+        let shift = (index % per_word) as u32 * self.width;
+        let mask = self.max() << shift;
+        let word = &mut self.words[index / per_word];
+
+        *word = (*word & !mask) | (value << shift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packing() {
+        for width in 1..=32 {
+            let mut packed = PackedVec::new(613, width);
+            let max = packed.max();
+
+            for i in 0..packed.len() {
+                packed.put(i, i as u64 % (max + 1));
+            }
+            for i in 0..packed.len() {
+                assert_eq!(packed.get(i), i as u64 % (max + 1));
+            }
+            let copy = PackedVec::from_words(packed.words().to_vec(), 613, width).unwrap();
+            assert_eq!(copy, packed);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A partitioned Bloom filter, where each hash function addresses its own slice of the bit
+//! vector.
+//!
+//! With `k` hash functions and `m` bits, the bit vector is split into `k` slices of `m / k`
+//! bits, and the `i`-th enhanced double hash of an item only sets a bit in slice `i`. Two
+//! hashes of the same item can therefore never collide, every item sets exactly `k` bits,
+//! and the fill ratio of each slice can be inspected on its own.
+use std::f64;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, DEFAULT_FALSE_POSITIVE_RATE};
+use crate::encoding::{DecodeError, Reader};
+
+/// A partitioned Bloom filter that keeps track of items of type `K`.
+#[derive(Debug)]
+pub struct PartitionedBloomFilter<K> {
+    bits: BitVec,
+    nhashes: usize,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> PartitionedBloomFilter<K> {
+    /// Return a new partitioned Bloom filter with a given approximate item capacity.
+    /// The default false positive probability is used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new partitioned Bloom filter given a size in bytes for the filter.
+    /// If the number of bits isn't a multiple of the number of hashes, the remaining
+    /// bits are left unused. A filter always has at least one bit per hash.
+    pub fn with_size(nbytes: usize) -> Self {
+        let nbits = nbytes * 8;
+        let capacity = bloom::optimal_capacity(nbits, DEFAULT_FALSE_POSITIVE_RATE);
+        let nhashes = bloom::optimal_hashes(nbits, capacity).max(1);
+        let nbits = nbits.max(nhashes);
+
+        Self::from_parts(BitVec::new(nbits), nhashes)
+    }
+
+    /// Return a new partitioned Bloom filter with a given approximate item capacity
+    /// and a desired false positive rate. The number of bits is rounded up to a
+    /// non-zero multiple of the number of hashes.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        let nbits = bloom::optimal_bits(capacity, fp_rate);
+        let nhashes = bloom::optimal_hashes(nbits, capacity).max(1);
+        let nbits = nbits.div_ceil(nhashes).max(1) * nhashes;
+
+        Self::from_parts(BitVec::new(nbits), nhashes)
+    }
+
+    /// Set an item in the filter. This operation is idempotent with regards
+    /// to each unique item.
+    pub fn insert(&mut self, item: &K) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        for i in 0..self.nhashes {
+            let index = self.index(h1, h2, i);
+            self.bits.set(index);
+        }
+    }
+
+    /// Return whether or not a given item is likely in the filter or not.
+    pub fn contains(&self, item: &K) -> bool {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        (0..self.nhashes).all(|i| self.bits.is_set(self.index(h1, h2, i)))
+    }
+}
+
+impl<K> PartitionedBloomFilter<K> {
+    fn from_parts(bits: BitVec, nhashes: usize) -> Self {
+        assert!(
+            nhashes > 0 && bits.len() >= nhashes,
+            "unable to partition {} bits into {} slices",
+            bits.len(),
+            nhashes
+        );
+        Self {
+            bits,
+            nhashes,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Set all bits to zero.
+    pub fn clear(&mut self) {
+        self.bits.clear();
+    }
+
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Number of hashes used (`k` parameter), which is also the number of slices.
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Return the number of bits in each slice.
+    pub fn slice_bits(&self) -> usize {
+        self.bits.len() / self.nhashes
+    }
+
+    /// Return the number of bits set in the given slice.
+    pub fn slice_ones(&self, slice: usize) -> usize {
+        assert!(
+            slice < self.nhashes,
+            "slice out of bounds: there are {} slices but the slice is {}",
+            self.nhashes,
+            slice
+        );
+        let len = self.slice_bits();
+
+        self.bits.count_ones_in(slice * len..(slice + 1) * len)
+    }
+
+    /// Return the fraction of bits set in each slice.
+    pub fn slice_fill(&self) -> Vec<f64> {
+        let len = self.slice_bits() as f64;
+
+        (0..self.nhashes)
+            .map(|i| self.slice_ones(i) as f64 / len)
+            .collect()
+    }
+
+    /// Return the expected false positive rate given the current fill of the slices.
+    /// This is the product of the fill ratios of all slices.
+    pub fn fp_rate(&self) -> f64 {
+        self.slice_fill().into_iter().product()
+    }
+
+    /// Count the approximate number of items in the filter.
+    ///
+    /// Each slice behaves like a single-hash filter of `m / k` bits, so with a total of `X`
+    /// bits set, the estimate is `ln(1 - X / m) / ln(1 - k / m)`.
+    pub fn count(&self) -> usize {
+        self.estimate_count(self.bits.count_ones())
+    }
+
+    /// Compute the approximate similarity between two filters using the Jaccard Index.
+    pub fn similarity(&self, other: &Self) -> f64 {
+        assert!(
+            self.is_comparable(other),
+            "unable to compare filters with different configurations"
+        );
+        let intersection = self.estimate_count(self.bits.intersection_count(&other.bits)) as f64;
+        let union = self.estimate_count(self.bits.union_count(&other.bits)) as f64;
+
+        intersection / union
+    }
+
+    /// Compute the approximate overlap between two filters using the overlap coefficient.
+    pub fn overlap(&self, other: &Self) -> f64 {
+        assert!(
+            self.is_comparable(other),
+            "unable to compare filters with different configurations"
+        );
+        let intersection = self.estimate_count(self.bits.intersection_count(&other.bits)) as f64;
+        let smallest = usize::min(self.count(), other.count()) as f64;
+
+        intersection / smallest
+    }
+
+    /// Compute the union of two partitioned Bloom filters.
+    pub fn union(&self, other: &Self) -> Self {
+        assert!(
+            self.is_comparable(other),
+            "unable to union filters with different configurations"
+        );
+        let bits = self.bits.union(&other.bits);
+
+        Self {
+            bits,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+
+    /// Compute the intersection of two partitioned Bloom filters.
+    pub fn intersection(&self, other: &Self) -> Self {
+        assert!(
+            self.is_comparable(other),
+            "unable to intersect filters with different configurations"
+        );
+        let bits = self.bits.intersection(&other.bits);
+
+        Self {
+            bits,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+
+    /// Check whether two filters can be compared, intersected and unioned.
+    pub fn is_comparable(&self, other: &Self) -> bool {
+        self.nhashes == other.nhashes
+            && self.bits.len() == other.bits.len()
+            && self.hashers[0].keys() == other.hashers[0].keys()
+            && self.hashers[1].keys() == other.hashers[1].keys()
+    }
+
+    /// Return the underlying bytes storage.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bits.as_bytes()
+    }
+
+    /// Estimate the number of items given the number of bits set.
+    fn estimate_count(&self, nbits_set: usize) -> usize {
+        let len = self.slice_bits() as f64;
+        let nbits = len * self.nhashes as f64;
+        let count = (1. - nbits_set as f64 / nbits).ln() / (1. - 1. / len).ln();
+
+        count.round() as usize
+    }
+
+    /// Return the index of the `i`-th hash, which lies within slice `i`.
+    fn index(&self, h1: u64, h2: u64, i: usize) -> usize {
+        let len = self.slice_bits();
+        i * len + bloom::bloom_hash(h1, h2, i as u64, len as u64) as usize
+    }
+}
+
+impl<K> Clone for PartitionedBloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            bits: self.bits.clone(),
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+impl<K> AsRef<[u8]> for PartitionedBloomFilter<K> {
+    fn as_ref(&self) -> &[u8] {
+        self.bits.as_bytes()
+    }
+}
+
+impl<K> PartialEq for PartitionedBloomFilter<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits == other.bits && self.nhashes == other.nhashes
+    }
+}
+
+impl<K> Eq for PartitionedBloomFilter<K> {}
+
+impl<K> From<PartitionedBloomFilter<K>> for Vec<u8> {
+    /// Encode the filter as its number of hashes and number of bits, followed by the bits.
+    fn from(other: PartitionedBloomFilter<K>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&(other.nhashes as u32).to_le_bytes());
+        bytes.extend_from_slice(&(other.bits.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(other.bits.as_bytes());
+        bytes
+    }
+}
+
+impl<K> TryFrom<&[u8]> for PartitionedBloomFilter<K> {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let nhashes = reader.u32()? as usize;
+        let nbits = reader.usize("nbits")?;
+
+        if nhashes == 0 {
+            return Err(DecodeError::InvalidField("nhashes"));
+        }
+        if nbits < nhashes {
+            return Err(DecodeError::InvalidField("nbits"));
+        }
+        let raw = reader.bytes(nbits.div_ceil(8))?;
+        let bits =
+            BitVec::from_bytes(raw.to_vec(), nbits).ok_or(DecodeError::InvalidField("bits"))?;
+
+        reader.finish()?;
+
+        Ok(Self::from_parts(bits, nhashes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partitioned_filter() {
+        let mut pbf = PartitionedBloomFilter::<u32>::new(1024);
+
+        for i in 0..1024 {
+            pbf.insert(&i);
+            assert!(
+                pbf.contains(&i),
+                "item {} should result in a positive inclusion",
+                i
+            );
+        }
+        let false_positives = (1024..11024).filter(|i| pbf.contains(i)).count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_slices() {
+        let mut pbf = PartitionedBloomFilter::<u32>::with_rate(100, 0.01);
+
+        assert_eq!(pbf.bits() % pbf.hashes(), 0);
+        assert_eq!(pbf.slice_bits() * pbf.hashes(), pbf.bits());
+
+        // Each item sets exactly one bit per slice.
+        pbf.insert(&42);
+        for i in 0..pbf.hashes() {
+            assert_eq!(pbf.slice_ones(i), 1);
+        }
+        assert_eq!(pbf.bits.count_ones(), pbf.hashes());
+    }
+
+    #[test]
+    fn test_with_size() {
+        let pbf = PartitionedBloomFilter::<String>::with_size(32 * 1024);
+
+        assert_eq!(pbf.bits(), 32 * 1024 * 8);
+    }
+
+    #[test]
+    fn test_count() {
+        let mut pbf = PartitionedBloomFilter::<u16>::new(4096);
+
+        for i in 0..12 {
+            pbf.insert(&i);
+        }
+        assert_eq!(pbf.count(), 12);
+
+        for i in 0..2048 {
+            pbf.insert(&i);
+        }
+        let count = pbf.count() as f64;
+        assert!((count - 2048.).abs() < 20., "count {} is off", count);
+    }
+
+    #[test]
+    fn test_union_intersection() {
+        let mut a = PartitionedBloomFilter::<u32>::new(4096);
+        let mut b = PartitionedBloomFilter::<u32>::new(4096);
+
+        for i in 0..128 {
+            a.insert(&i);
+        }
+        for i in 64..192 {
+            b.insert(&i);
+        }
+        let union = a.union(&b);
+        for i in 0..192 {
+            assert!(union.contains(&i));
+        }
+        let intersection = a.intersection(&b);
+        for i in 64..128 {
+            assert!(intersection.contains(&i));
+        }
+        assert!((a.similarity(&b) - 1. / 3.).abs() < 0.05);
+        assert!((a.overlap(&b) - 0.5).abs() < 0.05);
+        assert_eq!(a.similarity(&a), 1.0);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut a = PartitionedBloomFilter::<u32>::with_size(1024);
+
+        for i in 0..256 {
+            a.insert(&i);
+        }
+        let bytes: Vec<u8> = a.clone().into();
+        let b = PartitionedBloomFilter::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(a, b);
+        assert_eq!(a.hashes(), b.hashes());
+        for i in 0..256 {
+            assert!(b.contains(&i));
+        }
+        assert_eq!(
+            PartitionedBloomFilter::<u32>::try_from(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::UnexpectedEof)
+        );
+
+        // More slices than bits.
+        let mut invalid = bytes.clone();
+        invalid[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            PartitionedBloomFilter::<u32>::try_from(invalid.as_slice()),
+            Err(DecodeError::InvalidField("nbits"))
+        );
+    }
+
+    #[test]
+    fn test_tiny() {
+        // Fewer bits than items, for which the optimal number of hashes is zero.
+        let mut pbf = PartitionedBloomFilter::<u32>::with_rate(100, 0.9);
+
+        assert_eq!(pbf.hashes(), 1);
+        pbf.insert(&1);
+        assert!(pbf.contains(&1));
+    }
+
+    #[test]
+    fn test_zero_capacity() {
+        for mut pbf in [
+            PartitionedBloomFilter::<u32>::with_size(0),
+            PartitionedBloomFilter::<u32>::with_rate(0, 0.01),
+        ] {
+            assert!(pbf.hashes() >= 1);
+            pbf.insert(&1);
+            assert!(pbf.contains(&1));
+        }
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A prefix Bloom filter, for asking whether any key might start with a given prefix.
+//!
+//! Instead of whole keys, the filter stores prefixes of each key, one *level* per prefix
+//! length. Prefixes are either of fixed lengths, in bytes, or made of whole segments of
+//! hierarchical keys such as paths, split on a delimiter. Each level is a separate Bloom
+//! filter, so that the space and false positive rate of each level can be reported.
+//!
+//! A prefix that doesn't match a level exactly is truncated to the nearest shorter level,
+//! which never causes false negatives, but may cause more false positives. A prefix shorter
+//! than the first level can't be ruled out.
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// How prefixes are extracted from keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Prefixes {
+    /// Prefixes of the given lengths, in bytes.
+    Lengths(Vec<usize>),
+    /// Prefixes of `1` to `max_depth` segments, split on the delimiter. In this mode, a
+    /// query ending with the delimiter is made of whole segments: `a/b/` may match `a/b/c`,
+    /// but is ruled out by `a/bc` alone. Otherwise, the last segment of the query may be
+    /// partial, and only the segments before it are checked: `a/b` is checked as `a/`.
+    Delimiter {
+        /// Byte separating segments, eg. `b'/'`.
+        delimiter: u8,
+        /// Maximum number of segments in a prefix.
+        max_depth: usize,
+    },
+}
+
+impl Prefixes {
+    /// Return the number of levels.
+    fn levels(&self) -> usize {
+        match self {
+            Self::Lengths(lengths) => lengths.len(),
+            Self::Delimiter { max_depth, .. } => *max_depth,
+        }
+    }
+}
+
+/// Statistics of a single prefix level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LevelStats {
+    /// Length of the prefixes at this level: a number of bytes, or of segments.
+    pub prefix: usize,
+    /// Number of bits of this level.
+    pub bits: usize,
+    /// Number of bits of this level per inserted key.
+    pub bits_per_key: f64,
+    /// Estimated false positive rate of this level, given the fraction of bits set.
+    pub fp_rate: f64,
+}
+
+/// A single level of the filter.
+#[derive(Clone, Debug)]
+struct Level {
+    bits: BitVec,
+    nhashes: usize,
+}
+
+/// A Bloom filter over prefixes of byte-string keys, eg. paths or URLs.
+#[derive(Clone, Debug)]
+pub struct PrefixBloomFilter {
+    prefixes: Prefixes,
+    levels: Vec<Level>,
+    len: usize,
+    hashers: [SipHasher13; 2],
+}
+
+impl PrefixBloomFilter {
+    /// Return a new prefix Bloom filter for a given approximate number of keys, with the
+    /// given prefix lengths, in bytes. The default false positive probability is used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no lengths, or if a length is zero.
+    pub fn with_lengths(capacity: usize, lengths: &[usize]) -> Self {
+        Self::with_rate(
+            capacity,
+            DEFAULT_FALSE_POSITIVE_RATE,
+            Prefixes::Lengths(lengths.to_vec()),
+        )
+    }
+
+    /// Return a new prefix Bloom filter for a given approximate number of keys, storing
+    /// prefixes of up to `max_depth` segments split on the delimiter. The default false
+    /// positive probability is used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the maximum depth is zero.
+    pub fn with_delimiter(capacity: usize, delimiter: u8, max_depth: usize) -> Self {
+        Self::with_rate(
+            capacity,
+            DEFAULT_FALSE_POSITIVE_RATE,
+            Prefixes::Delimiter {
+                delimiter,
+                max_depth,
+            },
+        )
+    }
+
+    /// Return a new prefix Bloom filter for a given approximate number of keys, with a
+    /// desired false positive rate for each level. Since keys may share prefixes, each level
+    /// is sized for as many prefixes as keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no levels, or if a prefix length is zero.
+    pub fn with_rate(capacity: usize, fp_rate: f64, prefixes: Prefixes) -> Self {
+        let prefixes = match prefixes {
+            Prefixes::Lengths(mut lengths) => {
+                assert!(
+                    !lengths.contains(&0),
+                    "prefix lengths must be greater than zero"
+                );
+                lengths.sort_unstable();
+                lengths.dedup();
+
+                Prefixes::Lengths(lengths)
+            }
+            other => other,
+        };
+        assert!(
+            prefixes.levels() > 0,
+            "a prefix filter needs at least one level"
+        );
+
+        let nbits = bloom::optimal_bits(capacity, fp_rate);
+        let nhashes = bloom::optimal_hashes(nbits, capacity);
+        let level = Level {
+            bits: BitVec::new(nbits),
+            nhashes,
+        };
+
+        Self {
+            levels: vec![level; prefixes.levels()],
+            prefixes,
+            len: 0,
+            hashers: bloom::hashers(),
+        }
+    }
+
+    /// Add the prefixes of a key to the filter. Keys shorter than a level are not added to
+    /// that level.
+    pub fn insert(&mut self, key: impl AsRef<[u8]>) {
+        let key = key.as_ref();
+
+        for level in 0..self.levels.len() {
+            if let Some(prefix) = self.prefix(key, level) {
+                let (h1, h2) = bloom::sip_hashes(&self.hashers, prefix);
+                let level = &mut self.levels[level];
+
+                for i in 0..level.nhashes {
+                    let index = bloom::bloom_hash(h1, h2, i as u64, level.bits.len() as u64);
+                    level.bits.set(index as usize);
+                }
+            }
+        }
+        self.len += 1;
+    }
+
+    /// Return whether or not any key might start with the given prefix. There is a
+    /// possibility for a false positive, but a false negative will never occur.
+    pub fn may_contain_prefix(&self, prefix: impl AsRef<[u8]>) -> bool {
+        let Some((level, prefix)) = self.query(prefix.as_ref()) else {
+            return true;
+        };
+        let level = &self.levels[level];
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, prefix);
+
+        (0..level.nhashes).all(|i| {
+            let index = bloom::bloom_hash(h1, h2, i as u64, level.bits.len() as u64);
+            level.bits.is_set(index as usize)
+        })
+    }
+
+    /// Return the number of keys inserted.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether no keys were inserted.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return how prefixes are extracted from keys.
+    pub fn prefixes(&self) -> &Prefixes {
+        &self.prefixes
+    }
+
+    /// Return the total number of bits of all levels.
+    pub fn bits(&self) -> usize {
+        self.levels.iter().map(|l| l.bits.len()).sum()
+    }
+
+    /// Return the statistics of each level, from the shortest prefixes to the longest.
+    pub fn levels(&self) -> Vec<LevelStats> {
+        self.levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                let nbits = level.bits.len();
+                let fill = level.bits.count_ones() as f64 / nbits as f64;
+                let prefix = match &self.prefixes {
+                    Prefixes::Lengths(lengths) => lengths[i],
+                    Prefixes::Delimiter { .. } => i + 1,
+                };
+
+                LevelStats {
+                    prefix,
+                    bits: nbits,
+                    bits_per_key: nbits as f64 / self.len.max(1) as f64,
+                    fp_rate: fill.powi(level.nhashes as i32),
+                }
+            })
+            .collect()
+    }
+
+    /// Remove all keys.
+    pub fn clear(&mut self) {
+        self.levels.iter_mut().for_each(|l| l.bits.clear());
+        self.len = 0;
+    }
+
+    /// Return the prefix of a key at the given level, if the key is long enough.
+    fn prefix<'a>(&self, key: &'a [u8], level: usize) -> Option<&'a [u8]> {
+        match &self.prefixes {
+            Prefixes::Lengths(lengths) => key.get(..lengths[level]),
+            Prefixes::Delimiter { delimiter, .. } => {
+                let mut ends = key
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| *b == delimiter)
+                    .map(|(i, _)| i)
+                    .chain(Some(key.len()).filter(|n| *n > 0));
+
+                ends.nth(level).map(|end| &key[..end])
+            }
+        }
+    }
+
+    /// Return the level to query for a prefix, and the prefix truncated to that level.
+    /// Returns `None` if the prefix is shorter than all levels.
+    fn query<'a>(&self, prefix: &'a [u8]) -> Option<(usize, &'a [u8])> {
+        match &self.prefixes {
+            Prefixes::Lengths(lengths) => {
+                let level = lengths.iter().rposition(|l| *l <= prefix.len())?;
+                Some((level, &prefix[..lengths[level]]))
+            }
+            Prefixes::Delimiter {
+                delimiter,
+                max_depth,
+            } => {
+                // Without a trailing delimiter, the last segment may be partial.
+                let complete = prefix.iter().filter(|b| *b == delimiter).count();
+                let prefix = prefix.strip_suffix(&[*delimiter]).unwrap_or(prefix);
+
+                if prefix.is_empty() || complete == 0 {
+                    return None;
+                }
+                let level = complete.min(*max_depth) - 1;
+
+                Some((level, self.prefix(prefix, level)?))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lengths() {
+        let mut pbf = PrefixBloomFilter::with_lengths(1000, &[8, 2, 4]);
+
+        for i in 0..1000 {
+            pbf.insert(format!("user:{:04}:profile", i));
+        }
+        assert_eq!(pbf.prefixes(), &Prefixes::Lengths(vec![2, 4, 8]));
+
+        for i in 0..1000 {
+            let key = format!("user:{:04}:profile", i);
+
+            for n in 0..=key.len() {
+                assert!(
+                    pbf.may_contain_prefix(&key[..n]),
+                    "prefix {:?} resulted in a false negative",
+                    &key[..n]
+                );
+            }
+        }
+        assert!(!pbf.may_contain_prefix("item:"));
+        assert!(!pbf.may_contain_prefix("usex"));
+        // Shorter than any level.
+        assert!(pbf.may_contain_prefix("x"));
+    }
+
+    #[test]
+    fn test_delimiter() {
+        let mut pbf = PrefixBloomFilter::with_delimiter(1000, b'/', 3);
+
+        for ns in 0..10 {
+            for id in 0..100 {
+                pbf.insert(format!("ns{}/objects/{}/data", ns, id));
+            }
+        }
+        assert!(pbf.may_contain_prefix("ns0"));
+        assert!(pbf.may_contain_prefix("ns9/"));
+        assert!(pbf.may_contain_prefix("ns3/objects"));
+        assert!(pbf.may_contain_prefix("ns3/objects/42"));
+        // Deeper than the maximum depth: truncated to three segments.
+        assert!(pbf.may_contain_prefix("ns3/objects/42/data"));
+        assert!(pbf.may_contain_prefix("ns3/objects/42/metadata"));
+
+        assert!(!pbf.may_contain_prefix("ns10/"));
+        assert!(!pbf.may_contain_prefix("ns3/blobs/"));
+        assert!(!pbf.may_contain_prefix("ns3/objects/100/"));
+        assert!(!pbf.may_contain_prefix("ns10/objects"));
+        assert!(!pbf.may_contain_prefix("ns3/blobs/1"));
+        // A trailing segment may be partial: only the segments before it are checked.
+        assert!(pbf.may_contain_prefix("ns3/obj"));
+        assert!(pbf.may_contain_prefix("ns3/objects/4"));
+        assert!(pbf.may_contain_prefix("ns1"));
+        assert!(!pbf.may_contain_prefix("ns3/obj/"));
+    }
+
+    #[test]
+    fn test_level_stats() {
+        let mut pbf = PrefixBloomFilter::with_rate(10_000, 0.01, Prefixes::Lengths(vec![4, 8]));
+
+        for i in 0..10_000 {
+            pbf.insert(format!("{:08}", i));
+        }
+        let levels = pbf.levels();
+
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[0].prefix, 4);
+        assert_eq!(levels[1].prefix, 8);
+        assert_eq!(pbf.bits(), levels[0].bits + levels[1].bits);
+
+        // Keys share their first four digits, so the first level is nearly empty.
+        assert!(levels[0].fp_rate < 1e-6);
+        assert!((levels[1].fp_rate - 0.01).abs() < 0.003);
+        assert!((levels[1].bits_per_key - 9.59).abs() < 0.1);
+
+        let trials = 100_000;
+        let false_positives = (10_000..10_000 + trials)
+            .filter(|i| pbf.may_contain_prefix(format!("{:08}", i)))
+            .count();
+        let rate = false_positives as f64 / trials as f64;
+
+        assert!(rate < 0.015, "false positive rate {} is too high", rate);
+    }
+}
@@ -0,0 +1,793 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A quotient filter, which supports removing items, merging and resizing.
+//!
+//! As described by Bender et al. in *Don't Thrash: How to Cache Your Hash on Flash*, each
+//! item is reduced to a `p`-bit fingerprint, which is split into a `q`-bit *quotient* and
+//! an `r`-bit *remainder*, with `p = q + r`. The remainder is stored in a table of `2^q`
+//! slots, at the slot given by the quotient if it is free, or else as close after it as
+//! possible, using linear probing. Remainders sharing a quotient form a sorted *run*, and
+//! adjacent runs form a *cluster*. Three metadata bits per slot are enough to recover the
+//! quotient of every remainder:
+//!
+//! * `is_occupied`: some remainder has this slot as its quotient.
+//! * `is_continuation`: this slot holds a remainder of the same run as the previous slot.
+//! * `is_shifted`: the remainder in this slot is not in its quotient's slot.
+//!
+//! Since whole fingerprints can be recovered, a quotient filter can be resized or merged
+//! with another filter by re-inserting its fingerprints, without access to the original
+//! items. Doubling the number of slots moves one bit from the remainder to the quotient,
+//! so the false positive rate stays the same at a given load.
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom::{self, DEFAULT_FALSE_POSITIVE_RATE};
+use crate::cuckoo::InsertError;
+use crate::packed::PackedVec;
+
+/// Maximum load factor used when sizing a filter for a given capacity.
+const MAX_LOAD_FACTOR: f64 = 0.75;
+
+/// The slot's quotient has a run in the table.
+const OCCUPIED: u64 = 0b001;
+/// The slot continues the run of the previous slot.
+const CONTINUATION: u64 = 0b010;
+/// The slot's remainder is not in its quotient's slot.
+const SHIFTED: u64 = 0b100;
+/// Number of metadata bits per slot.
+const METADATA_BITS: u32 = 3;
+
+/// A quotient filter that keeps track of items of type `K`.
+#[derive(Debug)]
+pub struct QuotientFilter<K> {
+    /// Slots, each holding a remainder followed by three metadata bits.
+    slots: PackedVec,
+    quotient_bits: u32,
+    remainder_bits: u32,
+    len: usize,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> QuotientFilter<K> {
+    /// Return a new quotient filter with a given approximate item capacity.
+    /// The default false positive probability is used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new quotient filter with a given approximate item capacity and a desired
+    /// false positive rate. The number of slots is chosen so that the load factor stays
+    /// under 75% at capacity, and the remainder size so that `α / 2^r` doesn't exceed the
+    /// rate at that load `α`.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        let slots = (capacity as f64 / MAX_LOAD_FACTOR).ceil().max(2.);
+        let quotient_bits = slots.log2().ceil() as u32;
+        let remainder_bits = (MAX_LOAD_FACTOR / fp_rate).log2().ceil() as u32;
+
+        Self::with_params(quotient_bits, remainder_bits.clamp(1, 29))
+    }
+
+    /// Return a new quotient filter with `2^q` slots and `r`-bit remainders, for `q + r`
+    /// bit fingerprints.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the quotient size is zero, if the remainder size is not between `1` and
+    /// `29`, or if the fingerprint size exceeds `64` bits.
+    pub fn with_params(quotient_bits: u32, remainder_bits: u32) -> Self {
+        assert!(quotient_bits > 0, "quotient size must be greater than zero");
+        assert!(
+            quotient_bits < usize::BITS,
+            "quotient size of {} bits is too large",
+            quotient_bits
+        );
+        assert!(
+            (1..=29).contains(&remainder_bits),
+            "remainder size must be between 1 and 29 bits, got {}",
+            remainder_bits
+        );
+        assert!(
+            quotient_bits + remainder_bits <= 64,
+            "fingerprint size must not exceed 64 bits, got {}",
+            quotient_bits + remainder_bits
+        );
+        Self::empty(quotient_bits, remainder_bits)
+    }
+
+    /// Add an item to the filter. Inserting the same item more than once stores one
+    /// fingerprint per insert, so that it can be removed as many times.
+    ///
+    /// Returns [`InsertError::Full`] if all slots but one are in use, in which case the
+    /// filter is left unchanged.
+    pub fn insert(&mut self, item: &K) -> Result<(), InsertError> {
+        self.insert_fingerprint(self.fingerprint(item))
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        self.contains_fingerprint(self.fingerprint(item))
+    }
+
+    /// Remove one copy of an item from the filter. Returns `false` if the item was not
+    /// found.
+    ///
+    /// Only items that were inserted should be removed: removing a false positive removes
+    /// the fingerprint of another item.
+    pub fn remove(&mut self, item: &K) -> bool {
+        self.remove_fingerprint(self.fingerprint(item))
+    }
+
+    /// Return the fingerprint of an item.
+    fn fingerprint(&self, item: &K) -> u64 {
+        let (h1, _) = bloom::sip_hashes(&self.hashers, item);
+        h1 & mask(self.fingerprint_bits())
+    }
+}
+
+impl<K> QuotientFilter<K> {
+    /// Return the number of items in the filter.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the filter is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the maximum number of fingerprints the filter can hold. One slot is always
+    /// kept free, so that every cluster has a start.
+    pub fn capacity(&self) -> usize {
+        self.slots.len() - 1
+    }
+
+    /// Return the fraction of slots that are in use.
+    pub fn load_factor(&self) -> f64 {
+        self.len as f64 / self.slots.len() as f64
+    }
+
+    /// Return the number of slots, `2^q`.
+    pub fn slots(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Return the size of a quotient, in bits.
+    pub fn quotient_bits(&self) -> u32 {
+        self.quotient_bits
+    }
+
+    /// Return the size of a remainder, in bits.
+    pub fn remainder_bits(&self) -> u32 {
+        self.remainder_bits
+    }
+
+    /// Return the size of a fingerprint, in bits.
+    pub fn fingerprint_bits(&self) -> u32 {
+        self.quotient_bits + self.remainder_bits
+    }
+
+    /// Return the number of bits in this filter, including metadata bits.
+    pub fn bits(&self) -> usize {
+        self.slots.words().len() * 64
+    }
+
+    /// Return the approximate false positive rate at the current load `α`,
+    /// `1 - e^(-α / 2^r)`.
+    pub fn fp_rate(&self) -> f64 {
+        let r = self.remainder_bits as i32;
+        1. - (-self.load_factor() * 0.5_f64.powi(r)).exp()
+    }
+
+    /// Remove all items.
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.len = 0;
+    }
+
+    /// Iterate over the stored fingerprints, in slot order. Each fingerprint is returned
+    /// as its quotient followed by its remainder, in the low `q + r` bits.
+    pub fn fingerprints(&self) -> Fingerprints<'_, K> {
+        // Iteration must begin at the start of a cluster, to know the quotient of the
+        // first run. Since one slot is always free, there is one after it.
+        let start = (0..self.slots.len())
+            .find(|i| is_cluster_start(self.slots.get(*i)))
+            .unwrap_or(0);
+
+        Fingerprints {
+            filter: self,
+            index: start,
+            quotient: start,
+            visited: 0,
+        }
+    }
+
+    /// Return a copy of this filter with `2^q` slots for the given `q`, re-inserting every
+    /// fingerprint. The fingerprint size is preserved, so growing the table shortens the
+    /// remainders and vice-versa.
+    ///
+    /// Returns [`InsertError::Full`] if the fingerprints don't fit in the new table.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the new quotient size leaves no room for a remainder of `1` to `29` bits.
+    pub fn resize(&self, quotient_bits: u32) -> Result<Self, InsertError> {
+        let p = self.fingerprint_bits();
+
+        assert!(
+            quotient_bits < p && p - quotient_bits <= 29,
+            "unable to resize a filter with {}-bit fingerprints to {} quotient bits",
+            p,
+            quotient_bits
+        );
+        let mut resized = Self::empty(quotient_bits, p - quotient_bits);
+
+        if self.len > resized.capacity() {
+            return Err(InsertError::Full);
+        }
+        for fingerprint in self.fingerprints() {
+            resized.insert_fingerprint(fingerprint)?;
+        }
+        Ok(resized)
+    }
+
+    /// Merge this filter with another filter of the same fingerprint size, for example
+    /// two shards of different sizes. The merged filter has as many slots as the larger
+    /// of the two, doubled until the load factor is under 75%, if the remainders allow it.
+    /// Items present in both filters are stored twice.
+    ///
+    /// Returns [`InsertError::Full`] if the fingerprints don't fit in the merged filter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the filters have different fingerprint sizes.
+    pub fn merge(&self, other: &Self) -> Result<Self, InsertError> {
+        let p = self.fingerprint_bits();
+
+        assert_eq!(
+            p,
+            other.fingerprint_bits(),
+            "unable to merge filters with different fingerprint sizes"
+        );
+        let len = self.len + other.len;
+        let mut quotient_bits = self.quotient_bits.max(other.quotient_bits);
+
+        while quotient_bits + 1 < p
+            && len as f64 > (1usize << quotient_bits) as f64 * MAX_LOAD_FACTOR
+        {
+            quotient_bits += 1;
+        }
+        let mut merged = Self::empty(quotient_bits, p - quotient_bits);
+
+        if len > merged.capacity() {
+            return Err(InsertError::Full);
+        }
+        for fingerprint in self.fingerprints().chain(other.fingerprints()) {
+            merged.insert_fingerprint(fingerprint)?;
+        }
+        Ok(merged)
+    }
+
+    /// Return an empty filter of the given dimensions.
+    fn empty(quotient_bits: u32, remainder_bits: u32) -> Self {
+        Self {
+            slots: PackedVec::new(1 << quotient_bits, remainder_bits + METADATA_BITS),
+            quotient_bits,
+            remainder_bits,
+            len: 0,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Split a fingerprint into its quotient and remainder.
+    fn split(&self, fingerprint: u64) -> (usize, u64) {
+        let quotient = (fingerprint >> self.remainder_bits) as usize & (self.slots.len() - 1);
+        let remainder = fingerprint & mask(self.remainder_bits);
+
+        (quotient, remainder)
+    }
+
+    fn next(&self, i: usize) -> usize {
+        (i + 1) & (self.slots.len() - 1)
+    }
+
+    fn prev(&self, i: usize) -> usize {
+        i.wrapping_sub(1) & (self.slots.len() - 1)
+    }
+
+    /// Return the slot where the run of the given quotient starts, or would start.
+    fn find_run(&self, quotient: usize) -> usize {
+        // Walk back to the start of the cluster.
+        let mut b = quotient;
+        while self.slots.get(b) & SHIFTED != 0 {
+            b = self.prev(b);
+        }
+        // Walk forward, skipping one run for every occupied quotient until ours.
+        let mut s = b;
+        while b != quotient {
+            loop {
+                s = self.next(s);
+                if self.slots.get(s) & CONTINUATION == 0 {
+                    break;
+                }
+            }
+            loop {
+                b = self.next(b);
+                if self.slots.get(b) & OCCUPIED != 0 {
+                    break;
+                }
+            }
+        }
+        s
+    }
+
+    fn insert_fingerprint(&mut self, fingerprint: u64) -> Result<(), InsertError> {
+        if self.len >= self.capacity() {
+            return Err(InsertError::Full);
+        }
+        let (quotient, remainder) = self.split(fingerprint);
+        let canonical = self.slots.get(quotient);
+        let mut entry = remainder << METADATA_BITS;
+
+        self.len += 1;
+
+        if is_empty(canonical) {
+            self.slots.put(quotient, entry | OCCUPIED);
+            return Ok(());
+        }
+        self.slots.put(quotient, canonical | OCCUPIED);
+
+        let start = self.find_run(quotient);
+        let mut s = start;
+
+        if canonical & OCCUPIED != 0 {
+            // Find the insert position in the existing run, after any equal remainders.
+            loop {
+                if self.slots.get(s) >> METADATA_BITS > remainder {
+                    break;
+                }
+                s = self.next(s);
+
+                if self.slots.get(s) & CONTINUATION == 0 {
+                    break;
+                }
+            }
+            if s == start {
+                // The old start of the run becomes a continuation.
+                self.slots.put(start, self.slots.get(start) | CONTINUATION);
+            } else {
+                entry |= CONTINUATION;
+            }
+        }
+        if s != quotient {
+            entry |= SHIFTED;
+        }
+        self.shift_in(s, entry);
+
+        Ok(())
+    }
+
+    /// Insert an entry at the given slot, shifting the following entries of the cluster
+    /// to the right. Occupied bits stay with their slots.
+    fn shift_in(&mut self, mut s: usize, entry: u64) {
+        let mut current = entry;
+
+        loop {
+            let mut previous = self.slots.get(s);
+            let empty = is_empty(previous);
+
+            if !empty {
+                previous |= SHIFTED;
+
+                if previous & OCCUPIED != 0 {
+                    current |= OCCUPIED;
+                    previous &= !OCCUPIED;
+                }
+            }
+            self.slots.put(s, current);
+
+            if empty {
+                break;
+            }
+            current = previous;
+            s = self.next(s);
+        }
+    }
+
+    fn contains_fingerprint(&self, fingerprint: u64) -> bool {
+        let (quotient, remainder) = self.split(fingerprint);
+
+        if self.slots.get(quotient) & OCCUPIED == 0 {
+            return false;
+        }
+        let mut s = self.find_run(quotient);
+
+        loop {
+            let r = self.slots.get(s) >> METADATA_BITS;
+
+            if r == remainder {
+                return true;
+            } else if r > remainder {
+                return false;
+            }
+            s = self.next(s);
+
+            if self.slots.get(s) & CONTINUATION == 0 {
+                return false;
+            }
+        }
+    }
+
+    fn remove_fingerprint(&mut self, fingerprint: u64) -> bool {
+        let (quotient, remainder) = self.split(fingerprint);
+        let canonical = self.slots.get(quotient);
+
+        if canonical & OCCUPIED == 0 {
+            return false;
+        }
+        let mut s = self.find_run(quotient);
+
+        loop {
+            let r = self.slots.get(s) >> METADATA_BITS;
+
+            if r == remainder {
+                break;
+            } else if r > remainder {
+                return false;
+            }
+            s = self.next(s);
+
+            if self.slots.get(s) & CONTINUATION == 0 {
+                return false;
+            }
+        }
+        let removed = self.slots.get(s);
+        let run_start = is_run_start(removed);
+
+        // If this is the only entry of its run, the quotient is no longer occupied.
+        if run_start && self.slots.get(self.next(s)) & CONTINUATION == 0 {
+            self.slots
+                .put(quotient, self.slots.get(quotient) & !OCCUPIED);
+        }
+        self.shift_out(s, quotient);
+
+        if run_start {
+            let next = self.slots.get(s);
+            let mut updated = next;
+
+            // The next entry of the run becomes its start.
+            if updated & CONTINUATION != 0 {
+                updated &= !CONTINUATION;
+            }
+            if s == quotient && is_run_start(updated) {
+                updated &= !SHIFTED;
+            }
+            if updated != next {
+                self.slots.put(s, updated);
+            }
+        }
+        self.len -= 1;
+
+        true
+    }
+
+    /// Remove the entry at the given slot, whose quotient is given, shifting the following
+    /// entries of the cluster to the left. Entries that land back in their quotient's slot
+    /// are no longer shifted.
+    fn shift_out(&mut self, mut s: usize, mut quotient: usize) {
+        let origin = s;
+        let mut current = self.slots.get(s);
+        let mut sp = self.next(s);
+
+        loop {
+            let next = self.slots.get(sp);
+            let occupied = current & OCCUPIED;
+
+            if is_empty(next) || is_cluster_start(next) || sp == origin {
+                // An occupied slot can't be left without an entry: its run always follows.
+                debug_assert_eq!(occupied, 0);
+                self.slots.put(s, 0);
+                return;
+            }
+            let mut updated = next;
+
+            if is_run_start(next) {
+                // Track the quotient of the run being shifted.
+                loop {
+                    quotient = self.next(quotient);
+                    if self.slots.get(quotient) & OCCUPIED != 0 {
+                        break;
+                    }
+                }
+                if occupied != 0 && quotient == s {
+                    updated &= !SHIFTED;
+                }
+            }
+            self.slots.put(s, (updated & !OCCUPIED) | occupied);
+
+            s = sp;
+            sp = self.next(sp);
+            current = next;
+        }
+    }
+}
+
+impl<K> Clone for QuotientFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            slots: self.slots.clone(),
+            quotient_bits: self.quotient_bits,
+            remainder_bits: self.remainder_bits,
+            len: self.len,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+/// An iterator over the fingerprints of a [`QuotientFilter`].
+#[derive(Debug)]
+pub struct Fingerprints<'a, K> {
+    filter: &'a QuotientFilter<K>,
+    index: usize,
+    /// Quotient of the run at the current slot.
+    quotient: usize,
+    visited: usize,
+}
+
+impl<K> Iterator for Fingerprints<'_, K> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let filter = self.filter;
+
+        while self.visited < filter.len {
+            let entry = filter.slots.get(self.index);
+
+            if is_cluster_start(entry) {
+                self.quotient = self.index;
+            } else if is_run_start(entry) {
+                loop {
+                    self.quotient = filter.next(self.quotient);
+                    if filter.slots.get(self.quotient) & OCCUPIED != 0 {
+                        break;
+                    }
+                }
+            }
+            self.index = filter.next(self.index);
+
+            if !is_empty(entry) {
+                self.visited += 1;
+
+                let remainder = entry >> METADATA_BITS;
+                return Some(((self.quotient as u64) << filter.remainder_bits) | remainder);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.filter.len - self.visited;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<K> ExactSizeIterator for Fingerprints<'_, K> {}
+
+fn is_empty(slot: u64) -> bool {
+    slot & (OCCUPIED | CONTINUATION | SHIFTED) == 0
+}
+
+fn is_run_start(slot: u64) -> bool {
+    slot & CONTINUATION == 0 && slot & (OCCUPIED | SHIFTED) != 0
+}
+
+fn is_cluster_start(slot: u64) -> bool {
+    slot & (OCCUPIED | CONTINUATION | SHIFTED) == OCCUPIED
+}
+
+/// Return a mask of the low `n` bits.
+fn mask(n: u32) -> u64 {
+    if n >= 64 {
+        u64::MAX
+    } else {
+        (1 << n) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_insert_contains_remove() {
+        let mut qf = QuotientFilter::<u32>::new(1000);
+
+        for i in 0..1000 {
+            qf.insert(&i).unwrap();
+        }
+        assert_eq!(qf.len(), 1000);
+        assert!(qf.load_factor() <= MAX_LOAD_FACTOR);
+
+        for i in 0..1000 {
+            assert!(qf.contains(&i), "item {} resulted in a false negative", i);
+        }
+        for i in 0..500 {
+            assert!(qf.remove(&i));
+        }
+        assert_eq!(qf.len(), 500);
+
+        for i in 500..1000 {
+            assert!(qf.contains(&i), "item {} resulted in a false negative", i);
+        }
+    }
+
+    #[test]
+    fn test_fp_rate() {
+        let mut qf = QuotientFilter::<u32>::with_rate(10_000, 0.01);
+
+        for i in 0..10_000 {
+            qf.insert(&i).unwrap();
+        }
+        let trials = 100_000;
+        let false_positives = (10_000..10_000 + trials).filter(|i| qf.contains(i)).count();
+        let rate = false_positives as f64 / trials as f64;
+
+        assert!(qf.fp_rate() <= 0.01);
+        assert!(rate < 0.01, "false positive rate {} is too high", rate);
+    }
+
+    /// Check the table against a model of the fingerprints it should hold, under
+    /// random inserts and removes in a small, crowded table.
+    #[test]
+    fn test_model() {
+        let mut rng = Rng::new(7);
+
+        for round in 0..20 {
+            let mut qf = QuotientFilter::<u64>::with_params(6, 4);
+            let mut model = BTreeMap::<u64, usize>::new();
+
+            for _ in 0..500 {
+                let fingerprint = rng.below(1 << 10);
+
+                if rng.below(2) == 0 {
+                    if qf.insert_fingerprint(fingerprint).is_ok() {
+                        *model.entry(fingerprint).or_default() += 1;
+                    } else {
+                        assert_eq!(qf.len(), qf.capacity());
+                    }
+                } else {
+                    let expected = model.contains_key(&fingerprint);
+
+                    assert_eq!(
+                        qf.remove_fingerprint(fingerprint),
+                        expected,
+                        "round {}",
+                        round
+                    );
+                    if expected {
+                        let count = model.get_mut(&fingerprint).unwrap();
+                        *count -= 1;
+                        if *count == 0 {
+                            model.remove(&fingerprint);
+                        }
+                    }
+                }
+                for f in 0..1 << 10 {
+                    assert_eq!(qf.contains_fingerprint(f), model.contains_key(&f));
+                }
+                let mut fingerprints = qf.fingerprints().collect::<Vec<_>>();
+                let expected = model
+                    .iter()
+                    .flat_map(|(f, n)| std::iter::repeat_n(*f, *n))
+                    .collect::<Vec<_>>();
+
+                fingerprints.sort_unstable();
+                assert_eq!(fingerprints, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_full() {
+        let mut qf = QuotientFilter::<u32>::with_params(4, 8);
+
+        for i in 0..15 {
+            qf.insert(&i).unwrap();
+        }
+        assert_eq!(qf.insert(&15), Err(InsertError::Full));
+        assert_eq!(qf.len(), 15);
+
+        for i in 0..15 {
+            assert!(qf.contains(&i), "item {} was dropped", i);
+        }
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut qf = QuotientFilter::<&str>::new(100);
+
+        qf.insert(&"foo").unwrap();
+        qf.insert(&"foo").unwrap();
+        assert_eq!(qf.len(), 2);
+        assert_eq!(qf.fingerprints().count(), 2);
+
+        assert!(qf.remove(&"foo"));
+        assert!(qf.contains(&"foo"));
+        assert!(qf.remove(&"foo"));
+        assert!(!qf.contains(&"foo"));
+        assert!(!qf.remove(&"foo"));
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut qf = QuotientFilter::<u32>::with_params(10, 10);
+
+        for i in 0..700 {
+            qf.insert(&i).unwrap();
+        }
+        let grown = qf.resize(11).unwrap();
+
+        assert_eq!(grown.slots(), 2048);
+        assert_eq!(grown.remainder_bits(), 9);
+        assert_eq!(grown.len(), 700);
+
+        for i in 0..700 {
+            assert!(
+                grown.contains(&i),
+                "item {} resulted in a false negative",
+                i
+            );
+        }
+        let mut before = qf.fingerprints().collect::<Vec<_>>();
+        let mut after = grown.fingerprints().collect::<Vec<_>>();
+
+        before.sort_unstable();
+        after.sort_unstable();
+        assert_eq!(before, after);
+
+        assert_eq!(qf.resize(9).unwrap_err(), InsertError::Full);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = QuotientFilter::<u32>::with_params(8, 12);
+        let mut b = QuotientFilter::<u32>::with_params(10, 10);
+
+        for i in 0..150 {
+            a.insert(&i).unwrap();
+        }
+        for i in 1000..1700 {
+            b.insert(&i).unwrap();
+        }
+        let merged = a.merge(&b).unwrap();
+
+        assert_eq!(merged.len(), 850);
+        assert_eq!(merged.fingerprint_bits(), 20);
+        assert_eq!(merged.quotient_bits(), 11);
+
+        for i in (0..150).chain(1000..1700) {
+            assert!(
+                merged.contains(&i),
+                "item {} resulted in a false negative",
+                i
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_merge_incompatible() {
+        let a = QuotientFilter::<u32>::with_params(8, 12);
+        let b = QuotientFilter::<u32>::with_params(8, 10);
+
+        a.merge(&b).ok();
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A range filter over `u64` keys, for asking whether any key might be in `[lo, hi]`.
+//!
+//! As in Rosetta, described by Luo et al. in *Rosetta: A Robust Space-Time Optimized Range
+//! Filter for Key-Value Stores*, the filter is a hierarchy of Bloom filters over dyadic
+//! intervals: level `h` stores the prefix `x >> h` of every key `x`, which identifies the
+//! interval of `2^h` values containing it. A range is decomposed into the fewest dyadic
+//! intervals, each of which is looked up at its level. When an interval is found, its two
+//! halves are looked up in turn at the level below, down to single values, so that a false
+//! positive at one level is usually caught by the levels below it.
+//!
+//! With `L` levels, intervals are at most `2^(L-1)` values wide, so wider ranges are
+//! split into more lookups. The number of levels is thus picked from the widest range
+//! expected to be queried. Ranges that would need more than a fixed number of lookups are
+//! assumed to contain a key.
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom;
+
+/// Maximum number of top-level intervals looked up for a single range.
+const MAX_LOOKUPS: usize = 4096;
+
+/// A single level of the filter.
+#[derive(Clone, Debug)]
+struct Level {
+    bits: BitVec,
+    nhashes: usize,
+}
+
+/// A range filter over `u64` keys.
+#[derive(Clone, Debug)]
+pub struct RangeFilter {
+    levels: Vec<Level>,
+    len: usize,
+    hashers: [SipHasher13; 2],
+}
+
+impl RangeFilter {
+    /// Return a new range filter for a given approximate number of keys, using a total of
+    /// `bits_per_key` bits per key across all levels, with enough levels for ranges of up
+    /// to `max_width` values to be looked up as at most two intervals per level.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the maximum width is zero, or if there are fewer bits per key than levels.
+    pub fn new(capacity: usize, bits_per_key: usize, max_width: u64) -> Self {
+        assert!(
+            max_width > 0,
+            "maximum range width must be greater than zero"
+        );
+
+        let levels = (u64::BITS - (max_width - 1).leading_zeros()).max(1);
+
+        Self::with_levels(capacity, bits_per_key, levels as usize)
+    }
+
+    /// Return a new range filter for a given approximate number of keys, using a total of
+    /// `bits_per_key` bits per key, split evenly across the given number of levels.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of levels is not between `1` and `64`, or if there are fewer
+    /// bits per key than levels.
+    pub fn with_levels(capacity: usize, bits_per_key: usize, levels: usize) -> Self {
+        assert!(
+            (1..=64).contains(&levels),
+            "number of levels must be between 1 and 64, got {}",
+            levels
+        );
+        assert!(
+            bits_per_key >= levels,
+            "{} bits per key are not enough for {} levels",
+            bits_per_key,
+            levels
+        );
+        let capacity = capacity.max(1);
+        let nbits = capacity * bits_per_key / levels;
+        let level = Level {
+            bits: BitVec::new(nbits),
+            nhashes: bloom::optimal_hashes(nbits, capacity).max(1),
+        };
+
+        Self {
+            levels: vec![level; levels],
+            len: 0,
+            hashers: bloom::hashers(),
+        }
+    }
+
+    /// Add a key to the filter.
+    pub fn insert(&mut self, key: u64) {
+        for h in 0..self.levels.len() {
+            let (h1, h2) = self.hash(h, key >> h);
+            let level = &mut self.levels[h];
+
+            for i in 0..level.nhashes {
+                let index = bloom::bloom_hash(h1, h2, i as u64, level.bits.len() as u64);
+                level.bits.set(index as usize);
+            }
+        }
+        self.len += 1;
+    }
+
+    /// Return whether or not a given key is likely in the filter.
+    pub fn contains(&self, key: u64) -> bool {
+        self.may_contain_range(key, key)
+    }
+
+    /// Return whether or not any key might be in the inclusive range `[lo, hi]`. There is
+    /// a possibility for a false positive, but a false negative will never occur.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `lo` is greater than `hi`.
+    pub fn may_contain_range(&self, lo: u64, hi: u64) -> bool {
+        assert!(lo <= hi, "invalid range: {} is greater than {}", lo, hi);
+
+        let top = self.levels.len() as u32 - 1;
+        let end = hi as u128 + 1;
+        let mut x = lo as u128;
+        let mut lookups = 0;
+
+        // Split the range into the largest aligned intervals that fit.
+        while x < end {
+            let mut h = x.trailing_zeros().min(top);
+
+            while x + (1 << h) > end {
+                h -= 1;
+            }
+            if self.may_contain_interval(h as usize, (x >> h) as u64) {
+                return true;
+            }
+            x += 1 << h;
+
+            if h == top {
+                lookups += 1;
+
+                if lookups > MAX_LOOKUPS {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Return the number of keys inserted.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether no keys were inserted.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of levels.
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Return the total number of bits of all levels.
+    pub fn bits(&self) -> usize {
+        self.levels.iter().map(|l| l.bits.len()).sum()
+    }
+
+    /// Return the number of bits per inserted key.
+    pub fn bits_per_key(&self) -> f64 {
+        self.bits() as f64 / self.len.max(1) as f64
+    }
+
+    /// Remove all keys.
+    pub fn clear(&mut self) {
+        self.levels.iter_mut().for_each(|l| l.bits.clear());
+        self.len = 0;
+    }
+
+    /// Check whether the interval of `2^h` values with the given prefix might contain a
+    /// key, confirming positives with the two halves of the interval at the level below.
+    fn may_contain_interval(&self, h: usize, prefix: u64) -> bool {
+        let level = &self.levels[h];
+        let (h1, h2) = self.hash(h, prefix);
+        let found = (0..level.nhashes).all(|i| {
+            let index = bloom::bloom_hash(h1, h2, i as u64, level.bits.len() as u64);
+            level.bits.is_set(index as usize)
+        });
+
+        if !found {
+            return false;
+        }
+        if h == 0 {
+            return true;
+        }
+        self.may_contain_interval(h - 1, prefix << 1)
+            || self.may_contain_interval(h - 1, (prefix << 1) | 1)
+    }
+
+    /// Hash a prefix at the given level.
+    fn hash(&self, h: usize, prefix: u64) -> (u64, u64) {
+        bloom::sip_hashes(&self.hashers, &(h as u8, prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut rng = Rng::new(11);
+
+        for universe in [1 << 16, 1 << 32, u64::MAX] {
+            let mut filter = RangeFilter::new(1000, 64, 1 << 20);
+            let mut keys = BTreeSet::new();
+
+            for _ in 0..1000 {
+                let key = rng.below(universe);
+
+                filter.insert(key);
+                keys.insert(key);
+            }
+            for _ in 0..10_000 {
+                let lo = rng.below(universe);
+                let scale = rng.below(20);
+                let width = rng.below(1 << scale);
+                let hi = lo.saturating_add(width);
+
+                if keys.range(lo..=hi).next().is_some() {
+                    assert!(
+                        filter.may_contain_range(lo, hi),
+                        "range [{}, {}] resulted in a false negative",
+                        lo,
+                        hi
+                    );
+                }
+            }
+            for key in &keys {
+                assert!(filter.contains(*key));
+                assert!(filter.may_contain_range(key.saturating_sub(7), *key));
+            }
+        }
+    }
+
+    #[test]
+    fn test_edges() {
+        let mut filter = RangeFilter::new(10, 64, 1 << 16);
+
+        filter.insert(0);
+        filter.insert(u64::MAX);
+
+        assert!(filter.may_contain_range(0, 0));
+        assert!(filter.may_contain_range(u64::MAX, u64::MAX));
+        assert!(filter.may_contain_range(0, u64::MAX));
+        assert!(filter.may_contain_range(u64::MAX - 100, u64::MAX));
+        assert!(!filter.may_contain_range(1, 100));
+        assert!(!filter.may_contain_range(1 << 40, (1 << 40) + 1000));
+    }
+
+    #[test]
+    fn test_fp_rate() {
+        let mut rng = Rng::new(12);
+        let mut filter = RangeFilter::new(10_000, 64, 128);
+        let mut keys = BTreeSet::new();
+
+        // Keys are multiples of 2^16, so that ranges in between are empty.
+        for _ in 0..10_000 {
+            let key = rng.below(1 << 32) << 16;
+
+            filter.insert(key);
+            keys.insert(key);
+        }
+        assert!((filter.bits_per_key() - 64.).abs() < 1.);
+
+        for width in [1, 16, 256, 4096] {
+            let trials = 10_000;
+            let mut false_positives = 0;
+
+            for _ in 0..trials {
+                let lo = (rng.below(1 << 32) << 16) + 1 + rng.below((1 << 16) - 1 - width);
+                let hi = lo + width - 1;
+
+                if filter.may_contain_range(lo, hi) {
+                    false_positives += 1;
+                }
+            }
+            let rate = false_positives as f64 / trials as f64;
+
+            assert!(
+                rate < 0.05,
+                "false positive rate {} for ranges of width {}",
+                rate,
+                width
+            );
+        }
+    }
+
+    #[test]
+    fn test_wide_ranges() {
+        let mut rng = Rng::new(13);
+        // Eight bits per key at each level.
+        let mut filter = RangeFilter::new(1000, 8 * 32, 1 << 32);
+
+        assert_eq!(filter.levels(), 32);
+
+        // Keys are multiples of 2^40, so that ranges of 2^32 values in between are empty.
+        for _ in 0..1000 {
+            filter.insert(rng.below(1 << 24) << 40);
+        }
+        assert!(!filter.may_contain_range(1, 1 << 32));
+
+        let trials = 1000;
+        let false_positives = (0..trials)
+            .filter(|_| {
+                let lo = (rng.below(1 << 24) << 40) + 1 + rng.below(1 << 32);
+                filter.may_contain_range(lo, lo + (1 << 32) - 1)
+            })
+            .count();
+
+        assert!(
+            false_positives < trials / 20,
+            "{} false positives out of {}",
+            false_positives,
+            trials
+        );
+        assert_eq!(RangeFilter::new(1000, 64, 1).levels(), 1);
+        assert_eq!(RangeFilter::new(1000, 64, 3).levels(), 2);
+        assert_eq!(RangeFilter::new(1000, 64, u64::MAX).levels(), 64);
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A standard Ribbon filter, for space-optimal static sets.
+//!
+//! As described by Dillinger and Walzer in *Ribbon filter: practically smaller than Bloom
+//! and Xor*, each key defines a linear equation over GF(2): a 64-bit coefficient row `c`
+//! starting at row `s` of an `m`-row solution matrix `S`, and an `r`-bit fingerprint `f`,
+//! such that `c · S[s..s + 64] = f`. Because the coefficients of each equation are confined
+//! to a band of 64 rows, the system can be solved incrementally with Gaussian elimination
+//! as keys are added, then by back-substitution. With about `1.08` rows per key, a Ribbon
+//! filter uses close to `r` bits per key, for a false positive rate of `2^-r`.
+//!
+//! The solution matrix is stored *interleaved*: for each block of 64 rows, the `r`
+//! columns are stored as consecutive words, so that a query reads at most two blocks.
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom;
+use crate::rng::Rng;
+
+/// Width of the coefficient band, in rows.
+const RIBBON_WIDTH: usize = 64;
+
+/// Maximum number of seeds tried before construction fails.
+const MAX_ATTEMPTS: usize = 100;
+
+/// Initial number of rows per key. This is increased slightly after failed attempts.
+const INITIAL_OVERHEAD: f64 = 1.08;
+
+/// Seed of the random number generator used to pick hash seeds.
+const RNG_SEED: u64 = 0x51a3_9e07_c2d4_86bf;
+
+/// An error building a Ribbon filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// Banding found an inconsistent equation with every seed and row count that was
+    /// tried.
+    Unsolvable,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsolvable => write!(
+                f,
+                "unable to solve the ribbon system after {} seeds",
+                MAX_ATTEMPTS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A Ribbon filter holding items of type `K`.
+#[derive(Debug)]
+pub struct RibbonFilter<K> {
+    /// Interleaved solution matrix: `r` words per block of 64 rows.
+    solution: Vec<u64>,
+    nrows: usize,
+    result_bits: u32,
+    seed: u64,
+    len: usize,
+    hasher: SipHasher13,
+    key: PhantomData<K>,
+}
+
+/// The equation of a single key.
+#[derive(Clone, Copy)]
+struct Equation {
+    start: usize,
+    coefficients: u64,
+    result: u64,
+}
+
+impl<K: Hash> RibbonFilter<K> {
+    /// Build a filter from a set of keys, with the given number of bits per key `r`, for
+    /// a false positive rate of `2^-r`. Duplicate keys are ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bits per key are not between `1` and `32`.
+    pub fn from_keys<I>(keys: I, bits_per_key: u32) -> Result<Self, BuildError>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<K>,
+    {
+        assert!(
+            (1..=32).contains(&bits_per_key),
+            "bits per key must be between 1 and 32, got {}",
+            bits_per_key
+        );
+        let hasher = bloom::hashers()[0];
+        let mut hashes: Vec<u64> = keys
+            .into_iter()
+            .map(|k| bloom::sip_hash(&hasher, k.borrow()))
+            .collect();
+
+        hashes.sort_unstable();
+        hashes.dedup();
+
+        let mut rng = Rng::new(RNG_SEED);
+
+        for attempt in 0..MAX_ATTEMPTS {
+            let overhead = INITIAL_OVERHEAD + 0.01 * (attempt / 4) as f64;
+            let nrows = (hashes.len() as f64 * overhead).ceil() as usize + RIBBON_WIDTH;
+            let nrows = nrows.div_ceil(RIBBON_WIDTH) * RIBBON_WIDTH;
+            let mut filter = Self {
+                solution: Vec::new(),
+                nrows,
+                result_bits: bits_per_key,
+                seed: rng.u64(),
+                len: hashes.len(),
+                hasher,
+                key: PhantomData,
+            };
+            if filter.solve(&hashes) {
+                return Ok(filter);
+            }
+        }
+        Err(BuildError::Unsolvable)
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        let eq = self.equation(bloom::sip_hash(&self.hasher, item));
+        let block = eq.start / RIBBON_WIDTH;
+        let offset = eq.start % RIBBON_WIDTH;
+        let r = self.result_bits as usize;
+        let mut result = 0;
+
+        for j in 0..r {
+            let window = self.window(block, offset, j);
+            result |= (((eq.coefficients & window).count_ones() & 1) as u64) << j;
+        }
+        result == eq.result
+    }
+}
+
+impl<K> RibbonFilter<K> {
+    /// Return the number of keys the filter was built from.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the filter was built from an empty set.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.solution.len() * 64
+    }
+
+    /// Return the number of result bits per row, `r`.
+    pub fn result_bits(&self) -> u32 {
+        self.result_bits
+    }
+
+    /// Return the expected false positive rate, `2^-r`.
+    pub fn fp_rate(&self) -> f64 {
+        0.5_f64.powi(self.result_bits as i32)
+    }
+
+    /// Derive the equation of a key from its hash.
+    fn equation(&self, hash: u64) -> Equation {
+        let h = bloom::mix(hash ^ self.seed);
+        let start = ((h as u128 * (self.nrows - RIBBON_WIDTH + 1) as u128) >> 64) as usize;
+        // The first coefficient is always set, so that each equation has a pivot.
+        let coefficients = bloom::mix(h) | 1;
+        let result = bloom::mix(h ^ 0x9e37_79b9_7f4a_7c15) & ((1u64 << self.result_bits) - 1);
+
+        Equation {
+            start,
+            coefficients,
+            result,
+        }
+    }
+
+    /// Return the 64 solution bits of column `j`, starting at the given row.
+    fn window(&self, block: usize, offset: usize, j: usize) -> u64 {
+        let r = self.result_bits as usize;
+        let lo = self.solution[block * r + j] >> offset;
+
+        if offset == 0 {
+            return lo;
+        }
+        // Equations never extend past the last row, so the last block has no successor.
+        let hi = self.solution.get((block + 1) * r + j).copied().unwrap_or(0);
+
+        lo | (hi << (RIBBON_WIDTH - offset))
+    }
+
+    /// Band the equations of all keys, then back-substitute into the solution matrix.
+    /// Returns `false` if the system has no solution.
+    fn solve(&mut self, hashes: &[u64]) -> bool {
+        let mut coefficients = vec![0u64; self.nrows];
+        let mut results = vec![0u64; self.nrows];
+
+        // Banding: Gaussian elimination, one equation at a time.
+        for h in hashes {
+            let Equation {
+                mut start,
+                coefficients: mut c,
+                result: mut r,
+            } = self.equation(*h);
+
+            loop {
+                if coefficients[start] == 0 {
+                    coefficients[start] = c;
+                    results[start] = r;
+                    break;
+                }
+                c ^= coefficients[start];
+                r ^= results[start];
+
+                if c == 0 {
+                    // The equation is a combination of previous ones. Since hashes are
+                    // unique, this is only consistent if the results also cancel out.
+                    if r == 0 {
+                        break;
+                    }
+                    return false;
+                }
+                let shift = c.trailing_zeros() as usize;
+                start += shift;
+                c >>= shift;
+            }
+        }
+
+        // Back-substitution, from the last row up.
+        let r = self.result_bits as usize;
+        let nblocks = self.nrows / RIBBON_WIDTH;
+
+        self.solution = vec![0; nblocks * r];
+
+        for row in (0..self.nrows).rev() {
+            let block = row / RIBBON_WIDTH;
+            let offset = row % RIBBON_WIDTH;
+            let c = coefficients[row];
+
+            for j in 0..r {
+                // Rows without an equation are left as zero. Otherwise, the bit at this
+                // row is still zero, so the parity only covers the rows below it.
+                let window = self.window(block, offset, j);
+                let bit = ((results[row] >> j) ^ ((c & window).count_ones() as u64)) & 1;
+
+                if c != 0 {
+                    self.solution[block * r + j] |= bit << offset;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl<K> Clone for RibbonFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            solution: self.solution.clone(),
+            nrows: self.nrows,
+            result_bits: self.result_bits,
+            seed: self.seed,
+            len: self.len,
+            hasher: self.hasher,
+            key: self.key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ribbon_filter() {
+        let keys: Vec<u64> = (0..100_000).collect();
+        let filter = RibbonFilter::<u64>::from_keys(&keys, 8).unwrap();
+
+        for k in &keys {
+            assert!(filter.contains(k), "key {} resulted in a false negative", k);
+        }
+        assert_eq!(filter.len(), keys.len());
+    }
+
+    /// Bits per key against false positive rate, for 100'000 keys:
+    ///
+    /// | r  | bits/key | expected FP rate |
+    /// |----|----------|------------------|
+    /// | 1  | 1.08     | 50%              |
+    /// | 2  | 2.16     | 25%              |
+    /// | 4  | 4.32     | 6.25%            |
+    /// | 7  | 7.56     | 0.781%           |
+    /// | 8  | 8.64     | 0.391%           |
+    /// | 10 | 10.8     | 0.0977%          |
+    /// | 12 | 13.0     | 0.0244%          |
+    /// | 16 | 17.3     | 0.00153%         |
+    #[test]
+    fn test_bits_per_key_and_fp_rate() {
+        let table = [
+            (1, 1.08, 0.5),
+            (2, 2.16, 0.25),
+            (4, 4.32, 0.0625),
+            (7, 7.56, 0.00781),
+            (8, 8.64, 0.00391),
+            (10, 10.8, 0.000977),
+            (12, 13.0, 0.000244),
+            (16, 17.3, 0.0000153),
+        ];
+        let n = 100_000;
+        let keys: Vec<u64> = (0..n).collect();
+        let trials = 1_000_000;
+
+        for (r, bits_per_key, fp_rate) in table {
+            let filter = RibbonFilter::<u64>::from_keys(&keys, r).unwrap();
+            let actual_bits = filter.bits() as f64 / n as f64;
+            let false_positives = (n..n + trials).filter(|k| filter.contains(k)).count();
+            let actual_rate = false_positives as f64 / trials as f64;
+
+            assert_eq!(filter.fp_rate(), 0.5_f64.powi(r as i32));
+            assert!(
+                (actual_bits - bits_per_key).abs() < 0.1,
+                "r = {}: {} bits per key",
+                r,
+                actual_bits
+            );
+            // Allow for sampling error with very low rates.
+            assert!(
+                (actual_rate - fp_rate).abs() < fp_rate * 0.1 + 0.00002,
+                "r = {}: false positive rate {}",
+                r,
+                actual_rate
+            );
+        }
+    }
+
+    #[test]
+    fn test_small_sets() {
+        for n in 0..100u64 {
+            let filter = RibbonFilter::<u64>::from_keys(0..n, 8).unwrap();
+
+            assert_eq!(filter.len(), n as usize);
+            for k in 0..n {
+                assert!(filter.contains(&k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let keys = ["foo", "bar", "foo", "baz"];
+        let filter = RibbonFilter::<&str>::from_keys(keys, 12).unwrap();
+
+        assert_eq!(filter.len(), 3);
+        assert!(keys.iter().all(|k| filter.contains(k)));
+    }
+}
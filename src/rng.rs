@@ -0,0 +1,74 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A small, seedable pseudo-random number generator.
+//!
+//! Filters that need randomness use this instead of depending on an external crate. It
+//! isn't suitable for cryptographic use.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// A SplitMix64 generator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator from a seed. The same seed always yields the same sequence.
+    pub(crate) fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Create a generator with a seed derived from the process' random hashing keys.
+    pub(crate) fn from_entropy() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+
+        Self::new(hasher.finish())
+    }
+
+    /// Return the next random `u64`.
+    pub(crate) fn u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Return a random number in `0..n`.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        ((self.u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deterministic() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+
+        for _ in 0..100 {
+            assert_eq!(a.u64(), b.u64());
+        }
+        assert_ne!(Rng::new(1).u64(), Rng::new(2).u64());
+    }
+
+    #[test]
+    fn test_below() {
+        let mut rng = Rng::new(7);
+        let mut seen = [false; 10];
+
+        for _ in 0..1000 {
+            let n = rng.below(10) as usize;
+            seen[n] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A scalable Bloom filter, which grows as items are added.
+//!
+//! As described by Almeida, Baquero, Preguiça and Hutchison in *Scalable Bloom Filters*,
+//! the filter is a chain of plain Bloom filters, or *slices*. When the last slice reaches
+//! its capacity, a new one is added with `s` times the capacity and `r` times the false
+//! positive rate of the previous one. With an initial rate of `P(1 - r)`, the compounded
+//! false positive rate of all slices stays below `P`, however many slices are added.
+use std::hash::Hash;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, BloomFilter, DEFAULT_FALSE_POSITIVE_RATE};
+use crate::encoding::{DecodeError, Reader};
+
+/// The default capacity growth factor between slices, `s`.
+pub const DEFAULT_GROWTH_FACTOR: usize = 2;
+
+/// The default false positive tightening ratio between slices, `r`.
+pub const DEFAULT_TIGHTENING_RATIO: f64 = 0.85;
+
+/// A Bloom filter that grows to accomodate items of type `K`, while keeping its false
+/// positive rate bounded.
+#[derive(Clone, Debug)]
+pub struct ScalableBloomFilter<K> {
+    slices: Vec<Slice<K>>,
+    capacity: usize,
+    fp_rate: f64,
+    growth: usize,
+    tightening: f64,
+}
+
+/// A single filter in the chain.
+#[derive(Debug)]
+struct Slice<K> {
+    filter: BloomFilter<K>,
+    /// Number of items inserted into this slice.
+    items: usize,
+}
+
+impl<K> Clone for Slice<K> {
+    fn clone(&self) -> Self {
+        Self {
+            filter: self.filter.clone(),
+            items: self.items,
+        }
+    }
+}
+
+impl<K: Hash> ScalableBloomFilter<K> {
+    /// Return a new scalable Bloom filter with a given initial capacity.
+    /// The default false positive probability is used as the overall bound.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new scalable Bloom filter with a given initial capacity and
+    /// an overall false positive rate bound.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        Self::with_params(
+            capacity,
+            fp_rate,
+            DEFAULT_GROWTH_FACTOR,
+            DEFAULT_TIGHTENING_RATIO,
+        )
+    }
+
+    /// Return a new scalable Bloom filter with a given initial capacity, overall false
+    /// positive rate bound, capacity growth factor and tightening ratio.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the capacity is zero, the growth factor is smaller than `2`, or the
+    /// false positive rate or tightening ratio isn't strictly between `0` and `1`.
+    pub fn with_params(capacity: usize, fp_rate: f64, growth: usize, tightening: f64) -> Self {
+        assert!(capacity > 0, "capacity must be greater than zero");
+        assert!(
+            fp_rate > 0. && fp_rate < 1.,
+            "false positive rate must be between 0 and 1, got {}",
+            fp_rate
+        );
+        assert!(
+            growth >= 2,
+            "growth factor must be at least 2, got {}",
+            growth
+        );
+        assert!(
+            tightening > 0. && tightening < 1.,
+            "tightening ratio must be between 0 and 1, got {}",
+            tightening
+        );
+        let mut filter = Self {
+            slices: Vec::new(),
+            capacity,
+            fp_rate,
+            growth,
+            tightening,
+        };
+        filter.grow();
+        filter
+    }
+
+    /// Add an item to the filter, growing it if the current slice is full.
+    /// Items that are already likely in the filter are not added again.
+    pub fn insert(&mut self, item: &K) {
+        if self.contains(item) {
+            return;
+        }
+        let last = self.slices.len() - 1;
+
+        if self.slices[last].items >= self.slice_capacity(last) {
+            self.grow();
+        }
+        let slice = self.slices.last_mut().unwrap();
+
+        slice.filter.insert(item);
+        slice.items += 1;
+    }
+
+    /// Return whether or not a given item is likely in any of the slices.
+    pub fn contains(&self, item: &K) -> bool {
+        self.slices.iter().any(|s| s.filter.contains(item))
+    }
+
+    /// Compute the union of two scalable filters. The filters must have been created with
+    /// the same parameters, but may have a different number of slices.
+    pub fn union(&self, other: &Self) -> Self {
+        assert!(
+            self.is_comparable(other),
+            "unable to union filters with different configurations"
+        );
+        let (longer, shorter) = if self.slices.len() >= other.slices.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let mut slices = longer.slices.clone();
+
+        for (slice, s) in slices.iter_mut().zip(shorter.slices.iter()) {
+            slice.filter = slice.filter.union(&s.filter);
+            slice.items = slice.filter.count();
+        }
+        Self {
+            slices,
+            capacity: self.capacity,
+            fp_rate: self.fp_rate,
+            growth: self.growth,
+            tightening: self.tightening,
+        }
+    }
+
+    /// Check whether two filters can be unioned.
+    pub fn is_comparable(&self, other: &Self) -> bool {
+        self.capacity == other.capacity
+            && self.fp_rate == other.fp_rate
+            && self.growth == other.growth
+            && self.tightening == other.tightening
+    }
+
+    /// Count the approximate number of items in the filter.
+    pub fn count(&self) -> usize {
+        self.slices.iter().map(|s| s.filter.count()).sum()
+    }
+
+    /// Return the total capacity of all slices, after which a new slice is added.
+    pub fn capacity(&self) -> usize {
+        (0..self.slices.len()).map(|i| self.slice_capacity(i)).sum()
+    }
+
+    /// Return the number of slices in the filter.
+    pub fn slices(&self) -> usize {
+        self.slices.len()
+    }
+
+    /// Return the total number of bits used by all slices.
+    pub fn bits(&self) -> usize {
+        self.slices.iter().map(|s| s.filter.bits()).sum()
+    }
+
+    /// Return the compounded false positive rate bound of the current slices.
+    /// This never exceeds the rate the filter was created with.
+    pub fn fp_rate(&self) -> f64 {
+        1. - (0..self.slices.len())
+            .map(|i| 1. - self.slice_fp_rate(i))
+            .product::<f64>()
+    }
+
+    /// Remove all slices but the first, and clear it.
+    pub fn clear(&mut self) {
+        self.slices.truncate(1);
+        self.slices[0].filter.clear();
+        self.slices[0].items = 0;
+    }
+
+    fn grow(&mut self) {
+        let i = self.slices.len();
+        let filter = BloomFilter::with_rate(self.slice_capacity(i), self.slice_fp_rate(i));
+
+        self.slices.push(Slice { filter, items: 0 });
+    }
+
+    fn slice_capacity(&self, i: usize) -> usize {
+        self.capacity
+            .saturating_mul(self.growth.saturating_pow(i as u32))
+    }
+
+    fn slice_fp_rate(&self, i: usize) -> f64 {
+        self.fp_rate * (1. - self.tightening) * self.tightening.powi(i as i32)
+    }
+
+    /// Return the number of bits and hashes of the given slice, without allocating it, or
+    /// `None` if its capacity overflows. Sizes that don't fit in memory saturate.
+    fn slice_size(&self, i: usize) -> Option<(usize, usize)> {
+        let capacity = self
+            .capacity
+            .checked_mul(self.growth.checked_pow(u32::try_from(i).ok()?)?)?;
+        let nbits = bloom::optimal_bits(capacity, self.slice_fp_rate(i));
+
+        Some((nbits, bloom::optimal_hashes(nbits, capacity)))
+    }
+}
+
+impl<K> From<ScalableBloomFilter<K>> for Vec<u8> {
+    /// Encode the filter as its parameters, followed by the item count and bits of
+    /// each slice.
+    fn from(other: ScalableBloomFilter<K>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&(other.capacity as u64).to_le_bytes());
+        bytes.extend_from_slice(&other.fp_rate.to_le_bytes());
+        bytes.extend_from_slice(&(other.growth as u64).to_le_bytes());
+        bytes.extend_from_slice(&other.tightening.to_le_bytes());
+        bytes.extend_from_slice(&(other.slices.len() as u32).to_le_bytes());
+
+        for slice in other.slices {
+            bytes.extend_from_slice(&(slice.items as u64).to_le_bytes());
+            bytes.extend_from_slice(slice.filter.as_bytes());
+        }
+        bytes
+    }
+}
+
+impl<K: Hash> TryFrom<&[u8]> for ScalableBloomFilter<K> {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = Reader::new(bytes);
+        let capacity = reader.usize("capacity")?;
+        let fp_rate = reader.f64()?;
+        let growth = reader.usize("growth")?;
+        let tightening = reader.f64()?;
+        let nslices = reader.u32()? as usize;
+
+        if capacity == 0 {
+            return Err(DecodeError::InvalidField("capacity"));
+        }
+        if !(fp_rate > 0. && fp_rate < 1.) {
+            return Err(DecodeError::InvalidField("fp_rate"));
+        }
+        if growth < 2 {
+            return Err(DecodeError::InvalidField("growth"));
+        }
+        if !(tightening > 0. && tightening < 1.) {
+            return Err(DecodeError::InvalidField("tightening"));
+        }
+        if nslices == 0 {
+            return Err(DecodeError::InvalidField("slices"));
+        }
+        let mut filter = Self {
+            slices: Vec::new(),
+            capacity,
+            fp_rate,
+            growth,
+            tightening,
+        };
+        for i in 0..nslices {
+            let items = reader.usize("items")?;
+            let (nbits, nhashes) = filter
+                .slice_size(i)
+                .ok_or(DecodeError::InvalidField("slices"))?;
+            let raw = reader.bytes(nbits.div_ceil(8))?;
+            let bits =
+                BitVec::from_bytes(raw.to_vec(), nbits).ok_or(DecodeError::InvalidField("bits"))?;
+
+            filter.slices.push(Slice {
+                filter: BloomFilter::from_parts(bits, nhashes),
+                items,
+            });
+        }
+        reader.finish()?;
+
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grows() {
+        let mut sbf = ScalableBloomFilter::<u32>::new(100);
+
+        for i in 0..10_000 {
+            sbf.insert(&i);
+        }
+        assert!(sbf.slices() > 1);
+        assert!(sbf.capacity() >= 10_000);
+
+        for i in 0..10_000 {
+            assert!(sbf.contains(&i), "item {} resulted in a false negative", i);
+        }
+    }
+
+    #[test]
+    fn test_fp_rate_bound() {
+        let mut sbf = ScalableBloomFilter::<u32>::with_rate(64, 0.01);
+
+        for i in 0..50_000 {
+            sbf.insert(&i);
+        }
+        assert!(sbf.fp_rate() < 0.01);
+
+        let n = 100_000;
+        let false_positives = (50_000..50_000 + n).filter(|i| sbf.contains(i)).count();
+        let rate = false_positives as f64 / n as f64;
+
+        assert!(rate < 0.01, "false positive rate {} exceeds bound", rate);
+    }
+
+    #[test]
+    fn test_count() {
+        let mut sbf = ScalableBloomFilter::<u32>::new(128);
+
+        for i in 0..4096 {
+            sbf.insert(&i);
+        }
+        let count = sbf.count() as f64;
+
+        assert!(
+            (count - 4096.).abs() / 4096. < 0.05,
+            "count {} is off",
+            count
+        );
+    }
+
+    #[test]
+    fn test_union() {
+        let mut a = ScalableBloomFilter::<u32>::new(32);
+        let mut b = ScalableBloomFilter::<u32>::new(32);
+
+        for i in 0..1000 {
+            a.insert(&i);
+        }
+        for i in 1000..1100 {
+            b.insert(&i);
+        }
+        let union = a.union(&b);
+
+        assert_eq!(union.slices(), a.slices());
+        for i in 0..1100 {
+            assert!(union.contains(&i));
+        }
+        assert_eq!(b.union(&a).slices(), a.slices());
+    }
+
+    #[test]
+    #[should_panic(expected = "different configurations")]
+    fn test_union_incompatible() {
+        let a = ScalableBloomFilter::<u32>::new(32);
+        let b = ScalableBloomFilter::<u32>::new(64);
+
+        a.union(&b);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut a = ScalableBloomFilter::<u32>::new(16);
+
+        for i in 0..500 {
+            a.insert(&i);
+        }
+        let bytes: Vec<u8> = a.clone().into();
+        let b = ScalableBloomFilter::<u32>::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(a.slices(), b.slices());
+        assert_eq!(a.count(), b.count());
+        for i in 0..500 {
+            assert!(b.contains(&i));
+        }
+        assert_eq!(
+            ScalableBloomFilter::<u32>::try_from(&bytes[..bytes.len() - 1]).unwrap_err(),
+            DecodeError::UnexpectedEof
+        );
+
+        // A huge filter and slice count, without the bits to back them.
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(1u64 << 60).to_le_bytes());
+        bytes.extend_from_slice(&0.01f64.to_le_bytes());
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        bytes.extend_from_slice(&0.85f64.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+
+        assert_eq!(
+            ScalableBloomFilter::<u32>::try_from(bytes.as_slice()).unwrap_err(),
+            DecodeError::UnexpectedEof
+        );
+    }
+}
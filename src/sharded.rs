@@ -0,0 +1,282 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A sharded Bloom filter, for filters larger than a single allocation.
+//!
+//! The bits of the filter are spread across fixed-size shards, each a separate [`BitVec`],
+//! so that no single allocation exceeds the shard size. The size of the filter and all bit
+//! indices are `u64`, and only the offset of a bit within its shard is a `usize`, which
+//! lets filters of tens of billions of bits work on 32-bit platforms, provided the memory
+//! is available. Statistics are computed with `u64` and `f64` arithmetic, and don't
+//! overflow.
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// The default number of bits per shard, ie. 128 MiB shards.
+pub const DEFAULT_SHARD_BITS: u64 = 1 << 30;
+
+/// A Bloom filter that keeps track of items of type `K`, with its bits spread across
+/// shards.
+#[derive(Debug)]
+pub struct ShardedBloomFilter<K> {
+    shards: Vec<BitVec>,
+    shard_bits: u64,
+    nbits: u64,
+    nhashes: usize,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> ShardedBloomFilter<K> {
+    /// Return a new sharded Bloom filter with a given approximate item capacity.
+    /// The default false positive probability and shard size are used.
+    pub fn new(capacity: u64) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new sharded Bloom filter with a given approximate item capacity
+    /// and a desired false positive rate. The default shard size is used.
+    pub fn with_rate(capacity: u64, fp_rate: f64) -> Self {
+        Self::with_shard_bits(capacity, fp_rate, DEFAULT_SHARD_BITS)
+    }
+
+    /// Return a new sharded Bloom filter with a given approximate item capacity,
+    /// false positive rate and number of bits per shard.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the shard size is zero or doesn't fit in a `usize`.
+    pub fn with_shard_bits(capacity: u64, fp_rate: f64, shard_bits: u64) -> Self {
+        let nbits = bloom::optimal_bits_u64(capacity, fp_rate).max(1);
+        let nhashes = bloom::optimal_hashes_u64(nbits, capacity).max(1);
+
+        Self::with_size(nbits, nhashes, shard_bits)
+    }
+
+    /// Return a new sharded Bloom filter of the given size in bits and number of hashes,
+    /// with the given number of bits per shard.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size, the number of hashes or the shard size is zero, or if the shard
+    /// size doesn't fit in a `usize`.
+    pub fn with_size(nbits: u64, nhashes: usize, shard_bits: u64) -> Self {
+        assert!(nbits > 0, "number of bits must be greater than zero");
+        assert!(nhashes > 0, "number of hashes must be greater than zero");
+        assert!(shard_bits > 0, "shard size must be greater than zero");
+        assert!(
+            usize::try_from(shard_bits).is_ok(),
+            "shard size of {} bits is too large for this platform",
+            shard_bits
+        );
+        let shards = (0..nbits.div_ceil(shard_bits))
+            .map(|i| BitVec::new(u64::min(shard_bits, nbits - i * shard_bits) as usize))
+            .collect();
+
+        Self {
+            shards,
+            shard_bits,
+            nbits,
+            nhashes,
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Add an item to the filter.
+    pub fn insert(&mut self, item: &K) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        for i in 0..self.nhashes {
+            let (shard, offset) = self.locate(bloom::bloom_hash(h1, h2, i as u64, self.nbits));
+            self.shards[shard].set(offset);
+        }
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        (0..self.nhashes).all(|i| {
+            let (shard, offset) = self.locate(bloom::bloom_hash(h1, h2, i as u64, self.nbits));
+            self.shards[shard].is_set(offset)
+        })
+    }
+}
+
+impl<K> ShardedBloomFilter<K> {
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> u64 {
+        self.nbits
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Return the number of shards.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Return the number of bits per shard. The last shard may be smaller.
+    pub fn shard_bits(&self) -> u64 {
+        self.shard_bits
+    }
+
+    /// Return the number of bits that are set.
+    pub fn count_ones(&self) -> u64 {
+        self.shards.iter().map(|s| s.count_ones() as u64).sum()
+    }
+
+    /// Return the fraction of bits that are set.
+    pub fn fill_ratio(&self) -> f64 {
+        self.count_ones() as f64 / self.nbits as f64
+    }
+
+    /// Count the approximate number of items in the filter.
+    pub fn count(&self) -> u64 {
+        let nbits = self.nbits as f64;
+        let nhashes = self.nhashes as f64;
+        let count = -(nbits / nhashes) * (1. - self.fill_ratio()).ln();
+
+        count.round() as u64
+    }
+
+    /// Estimate the current false positive rate, given the fraction of bits set.
+    pub fn fp_rate(&self) -> f64 {
+        self.fill_ratio().powi(self.nhashes as i32)
+    }
+
+    /// Set all bits to zero.
+    pub fn clear(&mut self) {
+        self.shards.iter_mut().for_each(|s| s.clear());
+    }
+
+    /// Check whether two filters can be compared and unioned.
+    pub fn is_comparable(&self, other: &Self) -> bool {
+        self.nbits == other.nbits
+            && self.nhashes == other.nhashes
+            && self.shard_bits == other.shard_bits
+            && self.hashers[0].keys() == other.hashers[0].keys()
+            && self.hashers[1].keys() == other.hashers[1].keys()
+    }
+
+    /// Compute the union of two sharded filters.
+    pub fn union(&self, other: &Self) -> Self {
+        assert!(
+            self.is_comparable(other),
+            "unable to union filters with different configurations"
+        );
+        let shards = self
+            .shards
+            .iter()
+            .zip(&other.shards)
+            .map(|(a, b)| a.union(b))
+            .collect();
+
+        Self {
+            shards,
+            shard_bits: self.shard_bits,
+            nbits: self.nbits,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+
+    /// Return the shard and offset within the shard of a bit index.
+    fn locate(&self, index: u64) -> (usize, usize) {
+        (
+            (index / self.shard_bits) as usize,
+            (index % self.shard_bits) as usize,
+        )
+    }
+}
+
+impl<K> Clone for ShardedBloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            shard_bits: self.shard_bits,
+            nbits: self.nbits,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BloomFilter;
+
+    #[test]
+    fn test_sharded() {
+        let mut sbf = ShardedBloomFilter::<u32>::with_shard_bits(10_000, 0.01, 1000);
+
+        assert_eq!(sbf.shards() as u64, sbf.bits().div_ceil(1000));
+
+        for i in 0..10_000 {
+            sbf.insert(&i);
+        }
+        for i in 0..10_000 {
+            assert!(sbf.contains(&i), "item {} resulted in a false negative", i);
+        }
+        let false_positives = (10_000..20_000).filter(|i| sbf.contains(i)).count();
+
+        assert!(false_positives < 150, "{} false positives", false_positives);
+        assert!((sbf.count() as i64 - 10_000).abs() < 200);
+        assert!((sbf.fp_rate() - 0.01).abs() < 0.002);
+    }
+
+    #[test]
+    fn test_same_bits_as_bloom_filter() {
+        let mut sbf = ShardedBloomFilter::<u32>::with_shard_bits(1000, 0.01, 64);
+        let mut bf = BloomFilter::<u32>::with_rate(1000, 0.01);
+
+        for i in 0..1000 {
+            sbf.insert(&i);
+            bf.insert(&i);
+        }
+        assert_eq!(sbf.bits(), bf.bits() as u64);
+        assert_eq!(sbf.hashes(), bf.hashes());
+        assert_eq!(sbf.count_ones(), bf.bit_vec().count_ones() as u64);
+    }
+
+    #[test]
+    fn test_large_parameters() {
+        // Ten billion items, without allocating the filter.
+        let capacity = 10_000_000_000;
+        let nbits = bloom::optimal_bits_u64(capacity, 0.01);
+
+        assert!(nbits > u32::MAX as u64);
+        assert_eq!(nbits / capacity, 9);
+        assert_eq!(bloom::optimal_hashes_u64(nbits, capacity), 7);
+    }
+
+    #[test]
+    fn test_union() {
+        let mut a = ShardedBloomFilter::<u32>::with_shard_bits(100, 0.01, 100);
+        let mut b = a.clone();
+
+        a.insert(&1);
+        b.insert(&2);
+
+        let c = a.union(&b);
+
+        assert!(c.contains(&1));
+        assert!(c.contains(&2));
+        assert_eq!(c.count(), 2);
+
+        assert!(!ShardedBloomFilter::<u32>::with_shard_bits(100, 0.01, 50).is_comparable(&c));
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A sliding Bloom filter, which forgets items after a number of generations.
+//!
+//! Items are partitioned by age into `n` generations, each stored in its own Bloom filter.
+//! New items always go into the current generation, and [`SlidingBloomFilter::advance`]
+//! retires the oldest generation and starts a new, empty one in its place. Since an item
+//! is only ever forgotten when its whole generation is retired, an item is guaranteed to be
+//! found for `n - 1` advances after it was inserted, and never after `n` advances.
+//!
+//! Generations can also be advanced by a [`Clock`]. With a window of `w`, a generation is
+//! retired every `w / (n - 1)`, so that any item inserted within the last `w` is found.
+//!
+//! Each generation is sized for the given capacity, with a false positive rate chosen such
+//! that a lookup across all `n` generations stays within the requested rate.
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::time::{Duration, SystemTime};
+
+use crate::bloom::{BloomFilter, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// A source of time for advancing generations.
+pub trait Clock {
+    /// Return the current time, as a duration since an arbitrary, fixed epoch.
+    fn now(&self) -> Duration;
+}
+
+/// A clock that uses the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+impl<F: Fn() -> Duration> Clock for F {
+    fn now(&self) -> Duration {
+        self()
+    }
+}
+
+/// A Bloom filter that keeps track of items of type `K` seen in the last `n` generations.
+#[derive(Clone, Debug)]
+pub struct SlidingBloomFilter<K, C = SystemClock> {
+    /// Generations, from most to least recent.
+    generations: VecDeque<BloomFilter<K>>,
+    fp_rate: f64,
+    timer: Option<Timer<C>>,
+}
+
+/// Tracks when generations should be advanced.
+#[derive(Clone, Debug)]
+struct Timer<C> {
+    clock: C,
+    period: Duration,
+    /// Time at which the current generation started.
+    started: Duration,
+}
+
+impl<C: Clock> Timer<C> {
+    /// Return the number of periods elapsed since the current generation started.
+    fn elapsed(&self) -> usize {
+        let elapsed = self.clock.now().saturating_sub(self.started);
+        usize::try_from(elapsed.as_nanos() / self.period.as_nanos()).unwrap_or(usize::MAX)
+    }
+}
+
+impl<K: Hash> SlidingBloomFilter<K> {
+    /// Return a new sliding Bloom filter with the given number of generations, each with a
+    /// given approximate item capacity. The default false positive probability is used.
+    pub fn new(capacity: usize, generations: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE, generations)
+    }
+
+    /// Return a new sliding Bloom filter with the given number of generations, each with a
+    /// given approximate item capacity, and a false positive rate across all generations.
+    /// Generations are only advanced by calling [`SlidingBloomFilter::advance`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are fewer than two generations.
+    pub fn with_rate(capacity: usize, fp_rate: f64, generations: usize) -> Self {
+        assert!(
+            generations >= 2,
+            "a sliding filter needs at least two generations, got {}",
+            generations
+        );
+        // Choose the per-generation rate `p` such that `1 - (1 - p)^n` is the overall rate.
+        let rate = 1. - (1. - fp_rate).powf(1. / generations as f64);
+        let filter = BloomFilter::with_rate(capacity, rate);
+
+        Self {
+            generations: VecDeque::from(vec![filter; generations]),
+            fp_rate,
+            timer: None,
+        }
+    }
+}
+
+impl<K: Hash, C: Clock> SlidingBloomFilter<K, C> {
+    /// Return a new sliding Bloom filter that remembers items inserted within the given
+    /// window of time, as measured by the given clock. Each generation has the given
+    /// approximate item capacity.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are fewer than two generations, or if the window is too short to
+    /// be divided into generations.
+    pub fn with_clock(
+        capacity: usize,
+        fp_rate: f64,
+        generations: usize,
+        window: Duration,
+        clock: C,
+    ) -> Self {
+        let filter = SlidingBloomFilter::<K>::with_rate(capacity, fp_rate, generations);
+        let period = window / (generations as u32 - 1);
+
+        assert!(
+            !period.is_zero(),
+            "window of {:?} is too short for {} generations",
+            window,
+            generations
+        );
+        let started = clock.now();
+
+        Self {
+            generations: filter.generations,
+            fp_rate,
+            timer: Some(Timer {
+                clock,
+                period,
+                started,
+            }),
+        }
+    }
+
+    /// Add an item to the current generation.
+    pub fn insert(&mut self, item: &K) {
+        self.tick();
+        self.generations[0].insert(item);
+    }
+
+    /// Return whether or not a given item is likely in any of the live generations.
+    pub fn contains(&self, item: &K) -> bool {
+        self.generations
+            .iter()
+            .take(self.live())
+            .any(|g| g.contains(item))
+    }
+
+    /// Retire the oldest generation, and start a new one.
+    pub fn advance(&mut self) {
+        let mut oldest = self.generations.pop_back().unwrap();
+
+        oldest.clear();
+        self.generations.push_front(oldest);
+    }
+
+    /// Return the number of generations.
+    pub fn generations(&self) -> usize {
+        self.generations.len()
+    }
+
+    /// Return the false positive rate bound of a lookup across all generations.
+    pub fn fp_rate(&self) -> f64 {
+        self.fp_rate
+    }
+
+    /// Clear all generations.
+    pub fn clear(&mut self) {
+        self.generations.iter_mut().for_each(|g| g.clear());
+    }
+
+    /// Return the number of generations that haven't expired according to the clock.
+    fn live(&self) -> usize {
+        let expired = self.timer.as_ref().map_or(0, |t| t.elapsed());
+        self.generations.len().saturating_sub(expired)
+    }
+
+    /// Advance as many generations as the clock requires.
+    fn tick(&mut self) {
+        let Some(timer) = &mut self.timer else {
+            return;
+        };
+        let elapsed = timer.elapsed();
+
+        // Past the window, all generations expire and the count of periods doesn't matter.
+        if elapsed >= self.generations.len() {
+            timer.started = timer.clock.now();
+        } else {
+            for _ in 0..elapsed {
+                timer.started += timer.period;
+            }
+        }
+        for _ in 0..usize::min(elapsed, self.generations.len()) {
+            self.advance();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_advance() {
+        let mut sbf = SlidingBloomFilter::<u32>::new(100, 3);
+
+        sbf.insert(&1);
+        sbf.advance();
+        sbf.insert(&2);
+        sbf.advance();
+        sbf.insert(&3);
+
+        assert!(sbf.contains(&1));
+        assert!(sbf.contains(&2));
+        assert!(sbf.contains(&3));
+
+        sbf.advance();
+        assert!(!sbf.contains(&1));
+        assert!(sbf.contains(&2));
+        assert!(sbf.contains(&3));
+
+        sbf.advance();
+        sbf.advance();
+        assert!(!sbf.contains(&2));
+        assert!(!sbf.contains(&3));
+    }
+
+    #[test]
+    fn test_no_false_negatives_in_window() {
+        let generations = 4;
+        let mut sbf = SlidingBloomFilter::<u32>::new(100, generations);
+
+        for round in 0..20u32 {
+            for i in 0..100 {
+                sbf.insert(&(round * 100 + i));
+            }
+            let oldest = round.saturating_sub(generations as u32 - 1);
+            for item in oldest * 100..(round + 1) * 100 {
+                assert!(
+                    sbf.contains(&item),
+                    "item {} resulted in a false negative",
+                    item
+                );
+            }
+            sbf.advance();
+        }
+    }
+
+    #[test]
+    fn test_fp_rate() {
+        let mut sbf = SlidingBloomFilter::<u32>::with_rate(1000, 0.01, 5);
+
+        for g in 0..5u32 {
+            for i in 0..1000 {
+                sbf.insert(&(g * 1000 + i));
+            }
+            if g < 4 {
+                sbf.advance();
+            }
+        }
+        let trials = 100_000;
+        let false_positives = (10_000..10_000 + trials)
+            .filter(|i| sbf.contains(i))
+            .count();
+        let rate = false_positives as f64 / trials as f64;
+
+        assert!(rate < 0.015, "false positive rate {} exceeds bound", rate);
+    }
+
+    #[test]
+    fn test_clock() {
+        let now = Rc::new(Cell::new(Duration::from_secs(1000)));
+        let clock = {
+            let now = now.clone();
+            move || now.get()
+        };
+        let hour = Duration::from_secs(3600);
+        let mut sbf = SlidingBloomFilter::<&str, _>::with_clock(100, 0.01, 5, hour * 24, clock);
+
+        sbf.insert(&"foo");
+        now.set(now.get() + hour * 23);
+        sbf.insert(&"bar");
+        assert!(sbf.contains(&"foo"));
+
+        // Without any insert, generations expire as time passes.
+        now.set(now.get() + hour * 12);
+        assert!(!sbf.contains(&"foo"));
+        assert!(sbf.contains(&"bar"));
+
+        now.set(now.get() + hour * 48);
+        assert!(!sbf.contains(&"bar"));
+
+        sbf.insert(&"baz");
+        assert!(sbf.contains(&"baz"));
+        assert!(!sbf.contains(&"bar"));
+    }
+
+    #[test]
+    fn test_clock_jump() {
+        let now = Rc::new(Cell::new(Duration::ZERO));
+        let clock = {
+            let now = now.clone();
+            move || now.get()
+        };
+        let window = Duration::from_nanos(4);
+        let mut sbf = SlidingBloomFilter::<u32, _>::with_clock(100, 0.01, 5, window, clock);
+
+        sbf.insert(&1);
+        // Far more periods than fit in a `u32`, or a `usize` on 32-bit platforms.
+        now.set(Duration::from_secs(u64::MAX / 2));
+        assert!(!sbf.contains(&1));
+
+        sbf.insert(&2);
+        assert!(sbf.contains(&2));
+        now.set(now.get() + Duration::from_nanos(2));
+        assert!(sbf.contains(&2));
+    }
+}
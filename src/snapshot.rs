@@ -0,0 +1,345 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A Bloom filter with cheap, copy-on-write snapshots.
+//!
+//! The bits of the filter are split into fixed-size chunks, each behind an [`Arc`]. Taking
+//! a snapshot only clones the chunk pointers, and the writer copies a chunk the first time
+//! it sets a bit in it while the chunk is shared with a snapshot. Readers thus get a view
+//! of the filter as of the time of the snapshot, which is never affected by later inserts,
+//! while the cost of a snapshot is proportional to the number of chunks, and the cost of
+//! writes to the number of chunks they touch.
+//!
+//! Bits are laid out as in a [`BloomFilter`] of the same size, so that snapshots and
+//! filters can be converted to plain Bloom filters, and back.
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bitvec::BitVec;
+use crate::bloom::{self, BloomFilter, DEFAULT_FALSE_POSITIVE_RATE};
+
+/// The default number of bits per chunk, ie. 8 KiB chunks.
+pub const DEFAULT_CHUNK_BITS: usize = 1 << 16;
+
+/// A Bloom filter that keeps track of items of type `K`, and supports taking snapshots.
+#[derive(Debug)]
+pub struct SnapshotBloomFilter<K> {
+    /// The current state of the filter, which is shared with snapshots.
+    view: Snapshot<K>,
+}
+
+/// An immutable view of a [`SnapshotBloomFilter`] at a point in time.
+#[derive(Debug)]
+pub struct Snapshot<K> {
+    chunks: Vec<Arc<BitVec>>,
+    chunk_bits: usize,
+    nbits: usize,
+    nhashes: usize,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> SnapshotBloomFilter<K> {
+    /// Return a new snapshotting Bloom filter with a given approximate item capacity.
+    /// The default false positive probability and chunk size are used.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rate(capacity, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+
+    /// Return a new snapshotting Bloom filter with a given approximate item capacity
+    /// and a desired false positive rate. The default chunk size is used.
+    pub fn with_rate(capacity: usize, fp_rate: f64) -> Self {
+        Self::with_chunk_bits(capacity, fp_rate, DEFAULT_CHUNK_BITS)
+    }
+
+    /// Return a new snapshotting Bloom filter with a given approximate item capacity,
+    /// false positive rate and number of bits per chunk. Smaller chunks make writes
+    /// after a snapshot cheaper, and snapshots more expensive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the chunk size is zero or not a multiple of `8`.
+    pub fn with_chunk_bits(capacity: usize, fp_rate: f64, chunk_bits: usize) -> Self {
+        let nbits = bloom::optimal_bits(capacity, fp_rate);
+        let nhashes = bloom::optimal_hashes(nbits, capacity);
+
+        Self::from_parts(BitVec::new(nbits), nhashes, chunk_bits)
+    }
+
+    /// Add an item to the filter. Chunks that are shared with a snapshot are copied before
+    /// they are written to.
+    pub fn insert(&mut self, item: &K) {
+        let view = &mut self.view;
+        let (h1, h2) = bloom::sip_hashes(&view.hashers, item);
+
+        for i in 0..view.nhashes {
+            let index = bloom::bloom_hash(h1, h2, i as u64, view.nbits as u64) as usize;
+            let (chunk, offset) = (index / view.chunk_bits, index % view.chunk_bits);
+
+            if !view.chunks[chunk].is_set(offset) {
+                Arc::make_mut(&mut view.chunks[chunk]).set(offset);
+            }
+        }
+    }
+
+    /// Return whether or not a given item is likely in the filter.
+    pub fn contains(&self, item: &K) -> bool {
+        self.view.contains(item)
+    }
+}
+
+impl<K> SnapshotBloomFilter<K> {
+    /// Return a snapshot of the filter, which isn't affected by later inserts.
+    pub fn snapshot(&self) -> Snapshot<K> {
+        self.view.clone()
+    }
+
+    /// Return the number of bits in this filter.
+    pub fn bits(&self) -> usize {
+        self.view.nbits
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.view.nhashes
+    }
+
+    /// Return the number of chunks.
+    pub fn chunks(&self) -> usize {
+        self.view.chunks.len()
+    }
+
+    /// Return the number of bits per chunk. The last chunk may be smaller.
+    pub fn chunk_bits(&self) -> usize {
+        self.view.chunk_bits
+    }
+
+    /// Return the number of chunks that are shared with snapshots, and will be copied on
+    /// the next write to them.
+    pub fn shared_chunks(&self) -> usize {
+        self.view
+            .chunks
+            .iter()
+            .filter(|c| Arc::strong_count(c) > 1)
+            .count()
+    }
+
+    /// Set all bits to zero. Snapshots are not affected.
+    pub fn clear(&mut self) {
+        for chunk in &mut self.view.chunks {
+            *chunk = Arc::new(BitVec::new(chunk.len()));
+        }
+    }
+
+    /// Return a plain Bloom filter with the same bits.
+    pub fn to_bloom_filter(&self) -> BloomFilter<K> {
+        self.view.to_bloom_filter()
+    }
+
+    /// Build a filter from an existing bit vector and number of hashes, split into chunks
+    /// of the given size.
+    fn from_parts(bits: BitVec, nhashes: usize, chunk_bits: usize) -> Self {
+        assert!(
+            chunk_bits > 0 && chunk_bits % 8 == 0,
+            "chunk size must be a non-zero multiple of 8, got {}",
+            chunk_bits
+        );
+        let nbits = bits.len();
+        let chunks = bits
+            .as_bytes()
+            .chunks(chunk_bits / 8)
+            .enumerate()
+            .map(|(i, bytes)| {
+                let len = usize::min(chunk_bits, nbits - i * chunk_bits);
+                Arc::new(BitVec::from_bytes(bytes.to_vec(), len).unwrap())
+            })
+            .collect();
+
+        Self {
+            view: Snapshot {
+                chunks,
+                chunk_bits,
+                nbits,
+                nhashes,
+                hashers: bloom::hashers(),
+                key: PhantomData,
+            },
+        }
+    }
+}
+
+impl<K: Hash> Snapshot<K> {
+    /// Return whether or not a given item was likely in the filter at the time of the
+    /// snapshot.
+    pub fn contains(&self, item: &K) -> bool {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        (0..self.nhashes).all(|i| {
+            let index = bloom::bloom_hash(h1, h2, i as u64, self.nbits as u64) as usize;
+            self.chunks[index / self.chunk_bits].is_set(index % self.chunk_bits)
+        })
+    }
+}
+
+impl<K> Snapshot<K> {
+    /// Return the number of bits in this snapshot.
+    pub fn bits(&self) -> usize {
+        self.nbits
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Count the approximate number of items in the snapshot.
+    pub fn count(&self) -> usize {
+        let nbits = self.nbits as f64;
+        let nbits_set = self.chunks.iter().map(|c| c.count_ones()).sum::<usize>() as f64;
+        let nhashes = self.nhashes as f64;
+        let count = -(nbits / nhashes) * (1. - (nbits_set / nbits)).ln();
+
+        count.round() as usize
+    }
+
+    /// Return a plain Bloom filter with the bits of the snapshot.
+    pub fn to_bloom_filter(&self) -> BloomFilter<K> {
+        let bytes = self
+            .chunks
+            .iter()
+            .flat_map(|c| c.as_bytes())
+            .copied()
+            .collect();
+        let bits = BitVec::from_bytes(bytes, self.nbits).unwrap();
+
+        BloomFilter::from_parts(bits, self.nhashes)
+    }
+}
+
+impl<K> Clone for SnapshotBloomFilter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            view: self.view.clone(),
+        }
+    }
+}
+
+impl<K> Clone for Snapshot<K> {
+    fn clone(&self) -> Self {
+        Self {
+            chunks: self.chunks.clone(),
+            chunk_bits: self.chunk_bits,
+            nbits: self.nbits,
+            nhashes: self.nhashes,
+            hashers: self.hashers,
+            key: self.key,
+        }
+    }
+}
+
+impl<K> From<BloomFilter<K>> for SnapshotBloomFilter<K> {
+    fn from(other: BloomFilter<K>) -> Self {
+        let nhashes = other.hashes();
+
+        Self::from_parts(other.bit_vec().clone(), nhashes, DEFAULT_CHUNK_BITS)
+    }
+}
+
+impl<K> From<Snapshot<K>> for BloomFilter<K> {
+    fn from(other: Snapshot<K>) -> Self {
+        other.to_bloom_filter()
+    }
+}
+
+impl<K> From<SnapshotBloomFilter<K>> for BloomFilter<K> {
+    fn from(other: SnapshotBloomFilter<K>) -> Self {
+        other.to_bloom_filter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let mut sbf = SnapshotBloomFilter::<u32>::with_chunk_bits(1000, 0.01, 256);
+
+        for i in 0..500 {
+            sbf.insert(&i);
+        }
+        let snapshot = sbf.snapshot();
+
+        for i in 500..1000 {
+            sbf.insert(&i);
+        }
+        for i in 0..1000 {
+            assert!(sbf.contains(&i), "item {} resulted in a false negative", i);
+        }
+        for i in 0..500 {
+            assert!(
+                snapshot.contains(&i),
+                "item {} resulted in a false negative",
+                i
+            );
+        }
+        let leaked = (500..1000).filter(|i| snapshot.contains(i)).count();
+        assert!(leaked < 30, "{} later items are in the snapshot", leaked);
+        assert!((snapshot.count() as i64 - 500).abs() < 25);
+
+        sbf.clear();
+        assert!(snapshot.contains(&0));
+        assert!(!sbf.contains(&0));
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let mut sbf = SnapshotBloomFilter::<u32>::with_chunk_bits(10_000, 0.01, 1024);
+        let chunks = sbf.chunks();
+
+        assert_eq!(chunks, sbf.bits().div_ceil(1024));
+        assert_eq!(sbf.shared_chunks(), 0);
+
+        let snapshot = sbf.snapshot();
+        assert_eq!(sbf.shared_chunks(), chunks);
+
+        // An insert copies at most one chunk per hash.
+        sbf.insert(&1);
+        assert!(sbf.shared_chunks() < chunks);
+        assert!(sbf.shared_chunks() >= chunks - sbf.hashes());
+
+        // Chunks are only copied once.
+        let shared = sbf.shared_chunks();
+        sbf.insert(&1);
+        assert_eq!(sbf.shared_chunks(), shared);
+
+        // Inserting an item that is already present doesn't copy anything.
+        let _other = sbf.snapshot();
+        sbf.insert(&1);
+        assert_eq!(sbf.shared_chunks(), chunks);
+
+        drop(snapshot);
+    }
+
+    #[test]
+    fn test_bloom_filter_compatibility() {
+        let mut sbf = SnapshotBloomFilter::<u32>::with_chunk_bits(1000, 0.01, 64);
+        let mut bf = BloomFilter::<u32>::with_rate(1000, 0.01);
+
+        for i in 0..1000 {
+            sbf.insert(&i);
+            bf.insert(&i);
+        }
+        assert_eq!(sbf.snapshot().to_bloom_filter(), bf);
+        assert_eq!(BloomFilter::from(sbf.clone()), bf);
+
+        let converted = SnapshotBloomFilter::from(bf.clone());
+
+        assert_eq!(converted.bits(), bf.bits());
+        assert_eq!(BloomFilter::from(converted.snapshot()), bf);
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright (c) 2022 Alexis Sellier
+//
+// Licensed under the MIT license.
+
+//! A stable Bloom filter, for detecting duplicates in unbounded streams.
+//!
+//! As described by Fan Deng and Davood Rafiei in *Approximately Detecting Duplicates for
+//! Streaming Data using Stable Bloom Filters*, each of the `m` cells of the filter is a
+//! small counter with maximum value `Max`. Before an item is inserted, `P` cells chosen at
+//! random are decremented, then the item's `k` cells are set to `Max`. This evicts stale
+//! items over time, so that the fraction of zero cells converges to a constant, instead of
+//! the filter eventually filling up.
+//!
+//! In exchange, a stable Bloom filter has false negatives: an item seen a long time ago may
+//! have been evicted by the time it shows up again.
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher13;
+
+use crate::bloom;
+use crate::packed::PackedVec;
+use crate::rng::Rng;
+
+/// The default cell width, in bits.
+pub const DEFAULT_CELL_WIDTH: u32 = 3;
+
+/// A stable Bloom filter that keeps track of recently seen items of type `K`.
+#[derive(Clone, Debug)]
+pub struct StableBloomFilter<K> {
+    cells: PackedVec,
+    nhashes: usize,
+    ndecrements: usize,
+    rng: Rng,
+    hashers: [SipHasher13; 2],
+    key: PhantomData<K>,
+}
+
+impl<K: Hash> StableBloomFilter<K> {
+    /// Return a new stable Bloom filter with the given number of cells, that converges to
+    /// the given false positive rate. The random number generator is seeded randomly.
+    pub fn with_rate(ncells: usize, fp_rate: f64) -> Self {
+        Self::with_seed(ncells, fp_rate, Rng::from_entropy().u64())
+    }
+
+    /// Like [`StableBloomFilter::with_rate`], but seeds the random number generator used
+    /// to pick cells to decrement. Two filters with the same seed that see the same items
+    /// are identical.
+    pub fn with_seed(ncells: usize, fp_rate: f64, seed: u64) -> Self {
+        let nhashes = ((-fp_rate.ln() / std::f64::consts::LN_2).round() as usize).max(1);
+        let ndecrements = optimal_decrements(ncells, DEFAULT_CELL_WIDTH, nhashes, fp_rate);
+
+        Self::with_params(ncells, DEFAULT_CELL_WIDTH, nhashes, ndecrements, seed)
+    }
+
+    /// Return a new stable Bloom filter with the given number of cells, cell width in bits,
+    /// number of hashes `k`, number of cells decremented per insert `P`, and random seed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of hashes or decrements is zero or exceeds the number of
+    /// cells, or if the width is not between `1` and `32`.
+    pub fn with_params(
+        ncells: usize,
+        width: u32,
+        nhashes: usize,
+        ndecrements: usize,
+        seed: u64,
+    ) -> Self {
+        assert!(
+            nhashes > 0 && nhashes <= ncells,
+            "number of hashes must be between 1 and {}, got {}",
+            ncells,
+            nhashes
+        );
+        assert!(
+            ndecrements > 0 && ndecrements <= ncells,
+            "number of decrements must be between 1 and {}, got {}",
+            ncells,
+            ndecrements
+        );
+        Self {
+            cells: PackedVec::new(ncells, width),
+            nhashes,
+            ndecrements,
+            rng: Rng::new(seed),
+            hashers: bloom::hashers(),
+            key: PhantomData,
+        }
+    }
+
+    /// Add an item to the filter. This first decrements `P` random cells, then sets the
+    /// item's cells to their maximum value.
+    pub fn insert(&mut self, item: &K) {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+
+        self.decrement();
+        self.set(h1, h2);
+    }
+
+    /// Return whether or not a given item was likely seen recently.
+    pub fn contains(&self, item: &K) -> bool {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        self.is_set(h1, h2)
+    }
+
+    /// Check whether an item is a likely duplicate, then insert it. Returns `true` if the
+    /// item was likely seen recently.
+    pub fn insert_and_check(&mut self, item: &K) -> bool {
+        let (h1, h2) = bloom::sip_hashes(&self.hashers, item);
+        let duplicate = self.is_set(h1, h2);
+
+        self.decrement();
+        self.set(h1, h2);
+
+        duplicate
+    }
+
+    fn is_set(&self, h1: u64, h2: u64) -> bool {
+        (0..self.nhashes).all(|i| self.cells.get(self.index(h1, h2, i)) > 0)
+    }
+
+    fn set(&mut self, h1: u64, h2: u64) {
+        let max = self.cells.max();
+
+        for i in 0..self.nhashes {
+            let index = self.index(h1, h2, i);
+            self.cells.put(index, max);
+        }
+    }
+
+    /// Decrement `P` consecutive cells, starting from a random one.
+    fn decrement(&mut self) {
+        let len = self.cells.len();
+        let start = self.rng.below(len as u64) as usize;
+
+        for i in 0..self.ndecrements {
+            let index = (start + i) % len;
+            let value = self.cells.get(index);
+
+            if value > 0 {
+                self.cells.put(index, value - 1);
+            }
+        }
+    }
+
+    fn index(&self, h1: u64, h2: u64, i: usize) -> usize {
+        bloom::bloom_hash(h1, h2, i as u64, self.cells.len() as u64) as usize
+    }
+}
+
+impl<K> StableBloomFilter<K> {
+    /// Set all cells to zero.
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    /// Return the number of cells in this filter (`m` parameter).
+    pub fn cells(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Return the width of each cell, in bits.
+    pub fn cell_width(&self) -> u32 {
+        self.cells.width()
+    }
+
+    /// Number of hashes used (`k` parameter).
+    pub fn hashes(&self) -> usize {
+        self.nhashes
+    }
+
+    /// Number of cells decremented on each insert (`P` parameter).
+    pub fn decrements(&self) -> usize {
+        self.ndecrements
+    }
+
+    /// Return the current fraction of zero cells.
+    pub fn zeros(&self) -> f64 {
+        let zeros = (0..self.cells.len())
+            .filter(|i| self.cells.get(*i) == 0)
+            .count();
+
+        zeros as f64 / self.cells.len() as f64
+    }
+
+    /// Return the expected fraction of zero cells once the filter is stable.
+    ///
+    /// This is `(1 / (1 + 1 / (P(1/k - 1/m))))^Max`.
+    pub fn stable_zeros(&self) -> f64 {
+        let p = self.ndecrements as f64;
+        let k = self.nhashes as f64;
+        let m = self.cells.len() as f64;
+        let max = self.cells.max() as i32;
+
+        (1. / (1. + 1. / (p * (1. / k - 1. / m)))).powi(max)
+    }
+
+    /// Return the expected false positive rate once the filter is stable, ie. the chance
+    /// that all `k` cells of an item that was never inserted are non-zero.
+    pub fn fp_rate(&self) -> f64 {
+        (1. - self.stable_zeros()).powi(self.nhashes as i32)
+    }
+
+    /// Return the expected false negative rate, once the filter is stable, for an item that
+    /// was last inserted `gap` inserts ago.
+    ///
+    /// Each of the item's cells is modeled as a Markov chain: on every insert, it is
+    /// decremented with probability `P / m`, then reset to `Max` with probability `k / m`.
+    /// The item is a false negative if any of its cells reached zero.
+    pub fn fn_rate(&self, gap: usize) -> f64 {
+        let m = self.cells.len() as f64;
+        let decrement = self.ndecrements as f64 / m;
+        let reset = self.nhashes as f64 / m;
+        let max = self.cells.max() as usize;
+
+        // Probability of each cell value, starting from a freshly set cell.
+        let mut dist = vec![0.; max + 1];
+        dist[max] = 1.;
+
+        for _ in 0..gap {
+            let mut next = vec![0.; max + 1];
+
+            for (value, p) in dist.iter().enumerate() {
+                let lower = value.saturating_sub(1);
+                next[lower] += p * decrement;
+                next[value] += p * (1. - decrement);
+            }
+            let total: f64 = next.iter().sum();
+            next.iter_mut().for_each(|p| *p *= 1. - reset);
+            next[max] += total * reset;
+
+            dist = next;
+        }
+        1. - (1. - dist[0]).powi(self.nhashes as i32)
+    }
+}
+
+/// Return the number of cells to decrement on each insert (`P` parameter), so that a stable
+/// filter with the given number of cells, cell width and hashes converges to the given
+/// false positive rate.
+pub fn optimal_decrements(ncells: usize, width: u32, nhashes: usize, fp_rate: f64) -> usize {
+    let max = ((1u64 << width) - 1) as f64;
+    let k = nhashes as f64;
+    let m = ncells as f64;
+    let zeros = (1. - fp_rate.powf(1. / k)).powf(1. / max);
+    let p = 1. / ((1. / zeros - 1.) * (1. / k - 1. / m));
+
+    (p.ceil() as usize).clamp(1, ncells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stable_point() {
+        let mut sbf = StableBloomFilter::<u64>::with_seed(10_000, 0.01, 1);
+
+        for i in 0..200_000 {
+            sbf.insert(&i);
+        }
+        let expected = sbf.stable_zeros();
+        let actual = sbf.zeros();
+
+        assert!(
+            (expected - actual).abs() < 0.02,
+            "expected {} zeros, got {}",
+            expected,
+            actual
+        );
+        assert!((sbf.fp_rate() - 0.01).abs() < 0.002);
+
+        let trials = 100_000;
+        let false_positives = (1_000_000..1_000_000 + trials)
+            .filter(|i| sbf.contains(i))
+            .count();
+        let rate = false_positives as f64 / trials as f64;
+
+        assert!(rate < 0.02, "false positive rate {} is too high", rate);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let mut a = StableBloomFilter::<u64>::with_seed(1000, 0.01, 42);
+        let mut b = StableBloomFilter::<u64>::with_seed(1000, 0.01, 42);
+
+        for i in 0..10_000 {
+            assert_eq!(a.insert_and_check(&i), b.insert_and_check(&i));
+        }
+        assert_eq!(a.cells, b.cells);
+    }
+
+    #[test]
+    fn test_insert_and_check() {
+        let mut sbf = StableBloomFilter::<u64>::with_seed(10_000, 0.01, 7);
+
+        let duplicates = (0..1000).filter(|i| sbf.insert_and_check(i)).count();
+        assert!(duplicates < 20, "{} false duplicates", duplicates);
+
+        // Recent items are not evicted yet.
+        for i in 900..1000 {
+            assert!(
+                sbf.insert_and_check(&i),
+                "item {} resulted in a false negative",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_fn_rate() {
+        let sbf = StableBloomFilter::<u64>::with_seed(10_000, 0.01, 7);
+
+        assert_eq!(sbf.fn_rate(0), 0.);
+        assert!(sbf.fn_rate(100) < sbf.fn_rate(10_000));
+        assert!(sbf.fn_rate(1_000_000) > 0.99);
+    }
+
+    #[test]
+    fn test_optimal_decrements() {
+        let ncells = 10_000;
+        let p = optimal_decrements(ncells, DEFAULT_CELL_WIDTH, 7, 0.01);
+        let sbf = StableBloomFilter::<u64>::with_params(ncells, DEFAULT_CELL_WIDTH, 7, p, 0);
+
+        assert!(sbf.fp_rate() <= 0.01);
+    }
+}